@@ -0,0 +1,11 @@
+fn main() {
+  // The krb5 feature needs the bits of libkrb5 that depend on its headers
+  if std::env::var_os("CARGO_FEATURE_KRB5").is_some() {
+    println!("cargo:rerun-if-changed=src/krb5_shim.c");
+    cc::Build::new()
+      .file("src/krb5_shim.c")
+      .compile("clink_krb5_shim");
+    // After the shim, so the linker can find what it uses
+    println!("cargo:rustc-link-lib=krb5");
+  }
+}
@@ -1,33 +1,557 @@
+use crate::kerberos::{self, LoginError};
+use crate::token_cache::{Token, TokenCache};
 use http::status::StatusCode;
 use http::Uri;
+use isahc::config::{CaCertificate, Configurable};
+use isahc::error::ErrorKind;
 use isahc::{auth::Authentication, prelude::*, HttpClient, Request};
 use rpassword::prompt_password;
 use serde::{de, Deserialize, Serialize};
 use serde_json;
 use std::fmt;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::mpsc::channel;
 use std::sync::Arc;
 use std::sync::Mutex;
-use url::Url;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::{form_urlencoded, Url};
 use users::get_current_username;
 
-pub struct API {
-  token: Arc<Mutex<Option<String>>>,
+pub const DEFAULT_API_URL: &str = "https://drink.csh.rit.edu";
+/// Longest a request may take unless told otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest we'll wait to connect unless told otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Talks to the drink API, getting (and caching) tokens from SSO as needed.
+/// Clones share the same token and HTTP connection pool, so hand them out
+/// freely. Make one with [`DrinkClient::builder`].
+pub struct DrinkClient {
+  token: Arc<Mutex<Option<Token>>>,
+  token_cache: TokenCache,
   api_base_url: String,
-  password_function: Arc<Mutex<Box<PasswordFunction>>>,
+  auth: Arc<dyn AuthProvider>,
+  sso: SsoConfig,
+  client: HttpClient,
+  proxy: Option<Uri>,
+  timeout: Option<Duration>,
+  connect_timeout: Option<Duration>,
+  retry: RetryPolicy,
+  username: Option<String>,
+  password_source: PasswordSource,
+  /// `None` means [`DrinkClient::default_password_prompt`]
+  password_function: Arc<Mutex<Option<Box<PasswordFunction>>>>,
+}
+
+/// How a [`DrinkClient`] gets the tokens it sends to the drink API. The
+/// client holds on to (and caches) whatever [`AuthProvider::token`] hands
+/// back until it expires or the server turns it down.
+pub trait AuthProvider: Send + Sync {
+  /// Short name for messages, like `spnego`
+  fn name(&self) -> &'static str;
+
+  /// Gets a new token, logging in (and maybe asking for a password) if it
+  /// has to
+  fn token(&self, client: &DrinkClient) -> Result<Token, APIError>;
+
+  /// Like [`AuthProvider::token`], but never asks anyone for anything. `None`
+  /// means we'd have to log in first.
+  fn try_token(&self, client: &DrinkClient) -> Result<Option<Token>, APIError> {
+    self.token(client).map(Some)
+  }
+
+  /// Whether asking again could get a different token, so it's worth retrying
+  /// when the server says no and keeping tokens in the token cache
+  fn can_refresh(&self) -> bool {
+    true
+  }
+
+  /// Whether this needs a Kerberos ticket
+  fn uses_kerberos(&self) -> bool {
+    false
+  }
+
+  /// What to try when the server won't take our token, for
+  /// [`APIError::Unauthorized`]
+  fn unauthorized_hint(&self) -> &'static str {
+    "Log in again with `clink login`, or `clink doctor` to find out why"
+  }
+}
+
+/// Trades a Kerberos ticket for a token at SSO, running `kinit` (and asking
+/// for a password) when there's no ticket. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spnego;
+
+impl AuthProvider for Spnego {
+  fn name(&self) -> &'static str {
+    "spnego"
+  }
+
+  fn token(&self, client: &DrinkClient) -> Result<Token, APIError> {
+    if let Some(token) = client.request_token()? {
+      return Ok(token);
+    }
+    client.get_ticket()?;
+    // A fresh ticket SSO still won't take won't get any better by asking
+    // Kerberos for another one
+    client.request_token()?.ok_or_else(|| {
+      APIError::LoginFailed(LoginError::Other(
+        "SSO didn't accept the new ticket".to_string(),
+      ))
+    })
+  }
+
+  fn try_token(&self, client: &DrinkClient) -> Result<Option<Token>, APIError> {
+    client.request_token()
+  }
+
+  fn uses_kerberos(&self) -> bool {
+    true
+  }
+
+  fn unauthorized_hint(&self) -> &'static str {
+    "Did your Kerberos ticket expire?: `clink login`, or `clink doctor` to find out"
+  }
+}
+
+/// Sends the same token every time. It's never refreshed, so this is for
+/// API keys and bots that already have a token from somewhere else.
+#[derive(Clone)]
+pub struct StaticToken {
+  token: String,
+}
+
+impl StaticToken {
+  /// `token` is sent as `Authorization: Bearer <token>`
+  pub fn new(token: impl Into<String>) -> Self {
+    StaticToken {
+      token: token.into(),
+    }
+  }
+}
+
+impl fmt::Debug for StaticToken {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("StaticToken").finish_non_exhaustive()
+  }
+}
+
+impl AuthProvider for StaticToken {
+  fn name(&self) -> &'static str {
+    "token"
+  }
+
+  fn token(&self, _client: &DrinkClient) -> Result<Token, APIError> {
+    Ok(Token::new(format!("Bearer {}", self.token), None))
+  }
+
+  fn can_refresh(&self) -> bool {
+    false
+  }
+
+  fn unauthorized_hint(&self) -> &'static str {
+    "Was the token revoked, or is it for a different server?"
+  }
+}
+
+/// Trades an OpenID Connect refresh token for access tokens at SSO's token
+/// endpoint, so machines off the CSH network (and without Kerberos) can
+/// still get in. The tokens it gets go in the token cache along with the
+/// refresh token SSO rotates to, and that one is tried first from then on.
+pub struct RefreshToken {
+  refresh_token: Mutex<String>,
+}
+
+impl RefreshToken {
+  pub fn new(refresh_token: impl Into<String>) -> Self {
+    RefreshToken {
+      refresh_token: Mutex::new(refresh_token.into()),
+    }
+  }
+}
+
+impl fmt::Debug for RefreshToken {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("RefreshToken").finish_non_exhaustive()
+  }
+}
+
+impl AuthProvider for RefreshToken {
+  fn name(&self) -> &'static str {
+    "refresh-token"
+  }
+
+  fn token(&self, client: &DrinkClient) -> Result<Token, APIError> {
+    let mut refresh_token = self.refresh_token.lock().unwrap();
+    let token = client.refresh(&refresh_token)?;
+    if let Some(rotated) = &token.refresh_token {
+      *refresh_token = rotated.clone();
+    }
+    Ok(token)
+  }
+
+  fn unauthorized_hint(&self) -> &'static str {
+    "The refresh token expired or was used up: get a new one, or `clink login --device`"
+  }
+}
+
+/// What SSO's token endpoint sends back
+#[derive(Deserialize, Debug, Clone)]
+struct TokenResponse {
+  access_token: String,
+  expires_in: Option<u64>,
+  refresh_token: Option<String>,
+}
+
+impl TokenResponse {
+  fn into_token(self) -> Token {
+    Token::new(format!("Bearer {}", self.access_token), self.expires_in)
+      .with_refresh_token(self.refresh_token)
+  }
+}
+
+/// What SSO sends back when it won't give us what we asked for, like
+/// `invalid_grant` or `authorization_pending`
+#[derive(Deserialize, Debug, Clone)]
+struct OAuthError {
+  error: String,
+  error_description: Option<String>,
+}
+
+impl fmt::Display for OAuthError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.error_description {
+      Some(description) => write!(f, "{}: {}", self.error, description),
+      None => write!(f, "{}", self.error),
+    }
+  }
+}
+
+/// What SSO hands back when we start a device login. Show the user
+/// `verification_uri` and `user_code` (or just `verification_uri_complete`)
+/// and they can approve it from any browser.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceCode {
+  device_code: String,
+  pub user_code: String,
+  pub verification_uri: String,
+  /// `verification_uri` with the code already filled in
+  pub verification_uri_complete: Option<String>,
+  /// Seconds until the code is no good
+  pub expires_in: u64,
+  /// Seconds to wait between asking SSO whether it's been approved
+  #[serde(default = "default_interval")]
+  pub interval: u64,
+}
+
+/// What RFC 8628 says to use when SSO doesn't give an interval
+fn default_interval() -> u64 {
+  5
+}
+
+/// Where the password comes from when we need a Kerberos ticket
+#[derive(Debug, Clone, Default)]
+pub enum PasswordSource {
+  /// Ask with the password prompt (on the terminal, unless it's been
+  /// replaced), until the password is right
+  #[default]
+  Prompt,
+  /// Read it from stdin, to the end
+  Stdin,
+  /// Read it from a file
+  File(PathBuf),
+  /// Run this with `sh -c` and use the first line it prints, like
+  /// `pass show csh`
+  Command(String),
+  /// Don't use a password, get the ticket with the keys in this keytab
+  Keytab(PathBuf),
+}
+
+/// Sets up a [`DrinkClient`]. Everything has a default, so
+/// `DrinkClient::builder().build()` talks to drink.csh.rit.edu the same way
+/// the CLI does.
+pub struct DrinkClientBuilder {
+  api_base_url: String,
+  auth: Arc<dyn AuthProvider>,
+  sso: SsoConfig,
+  token_cache: TokenCache,
+  client: Option<HttpClient>,
+  timeout: Option<Duration>,
+  connect_timeout: Option<Duration>,
+  proxy: Option<Uri>,
+  ca_bundle: Option<PathBuf>,
+  user_agent: String,
+  retry: RetryPolicy,
+  username: Option<String>,
+  password_source: PasswordSource,
+  password_function: Option<Box<PasswordFunction>>,
+}
+
+impl Default for DrinkClientBuilder {
+  fn default() -> Self {
+    DrinkClientBuilder {
+      api_base_url: DEFAULT_API_URL.to_string(),
+      auth: Arc::new(Spnego),
+      sso: SsoConfig::default(),
+      token_cache: TokenCache::default(),
+      client: None,
+      timeout: Some(DEFAULT_TIMEOUT),
+      connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+      proxy: None,
+      ca_bundle: None,
+      user_agent: format!("clink/{}", env!("CARGO_PKG_VERSION")),
+      retry: RetryPolicy::default(),
+      username: None,
+      password_source: PasswordSource::default(),
+      password_function: None,
+    }
+  }
+}
+
+impl DrinkClientBuilder {
+  /// Drink API base URL [default: <https://drink.csh.rit.edu>]
+  pub fn base_url(mut self, api_base_url: impl Into<String>) -> Self {
+    self.api_base_url = api_base_url.into();
+    self
+  }
+
+  /// How to get tokens [default: [`Spnego`]]
+  pub fn auth(mut self, auth: impl AuthProvider + 'static) -> Self {
+    self.auth = Arc::new(auth);
+    self
+  }
+
+  /// Where to get tokens from, not used with [`StaticToken`]
+  pub fn sso(mut self, sso: SsoConfig) -> Self {
+    self.sso = sso;
+    self
+  }
+
+  /// Where to keep tokens between runs [default: the user's cache directory]
+  pub fn token_cache(mut self, token_cache: TokenCache) -> Self {
+    self.token_cache = token_cache;
+    self
+  }
+
+  /// HTTP client to send everything with, instead of the one we'd make. It
+  /// must not follow redirects, we need to see the one SSO sends back with
+  /// the token in it. The proxy, CA bundle and user agent are up to you then.
+  pub fn http_client(mut self, client: HttpClient) -> Self {
+    self.client = Some(client);
+    self
+  }
+
+  /// Longest a whole request (including reading the response) may take
+  /// [default: [`DEFAULT_TIMEOUT`]]
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Longest we'll wait to connect to a server
+  /// [default: [`DEFAULT_CONNECT_TIMEOUT`]]
+  pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+    self.connect_timeout = Some(timeout);
+    self
+  }
+
+  /// HTTP proxy to go through, like `http://proxy.example:3128`
+  /// [default: whatever `https_proxy`/`http_proxy` say]
+  pub fn proxy(mut self, proxy: Uri) -> Self {
+    self.proxy = Some(proxy);
+    self
+  }
+
+  /// PEM file with the certificate authorities to trust, instead of the
+  /// system's
+  pub fn ca_bundle(mut self, path: impl Into<PathBuf>) -> Self {
+    self.ca_bundle = Some(path.into());
+    self
+  }
+
+  /// `User-Agent` for every request [default: `clink/<version>`]
+  pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+    self.user_agent = user_agent.into();
+    self
+  }
+
+  /// How to retry GETs that fail because of the network
+  /// [default: [`RetryPolicy::default`]]
+  pub fn retry(mut self, retry: RetryPolicy) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  /// Who to get a Kerberos ticket for [default: `CLINK_USERNAME`, or the
+  /// user we're running as]
+  pub fn username(mut self, username: impl Into<String>) -> Self {
+    self.username = Some(username.into());
+    self
+  }
+
+  /// Called with the username when `kinit` needs a password
+  /// [default: [`DrinkClient::default_password_prompt`]]
+  pub fn password_prompt(mut self, prompt: Box<PasswordFunction>) -> Self {
+    self.password_function = Some(prompt);
+    self
+  }
+
+  /// Where to get the password from when we need a Kerberos ticket. Anything
+  /// but [`PasswordSource::Prompt`] gets one try, since asking again won't
+  /// change the answer. [default: [`PasswordSource::Prompt`]]
+  pub fn password_source(mut self, source: PasswordSource) -> Self {
+    self.password_source = source;
+    self
+  }
+
+  pub fn build(self) -> Result<DrinkClient, APIError> {
+    let client = match self.client {
+      Some(client) => client,
+      None => {
+        // One client for everything, so requests share its connection pool
+        let mut builder = HttpClient::builder().default_header("User-Agent", self.user_agent);
+        // Leaving it unset lets curl pick up the usual proxy variables
+        if let Some(proxy) = self.proxy.clone() {
+          builder = builder.proxy(Some(proxy));
+        }
+        if let Some(timeout) = self.timeout {
+          builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+          builder = builder.connect_timeout(timeout);
+        }
+        if let Some(path) = self.ca_bundle {
+          builder = builder.ssl_ca_certificate(CaCertificate::file(path));
+        }
+        builder.build().map_err(APIError::IsahcError)?
+      }
+    };
+    Ok(DrinkClient {
+      token: Arc::new(Mutex::new(None)),
+      token_cache: self.token_cache,
+      api_base_url: self.api_base_url,
+      auth: self.auth,
+      sso: self.sso,
+      client,
+      proxy: self.proxy,
+      timeout: self.timeout,
+      connect_timeout: self.connect_timeout,
+      retry: self.retry,
+      username: self.username,
+      password_source: self.password_source,
+      password_function: Arc::new(Mutex::new(self.password_function)),
+    })
+  }
+}
+
+/// Where to find the Keycloak server we get tokens from, and the Kerberos
+/// realm we log in to when we don't have a ticket
+#[derive(Debug, Clone)]
+pub struct SsoConfig {
+  /// Base URL of the Keycloak server (everything before `/realms/...`)
+  pub url: String,
+  /// Keycloak realm
+  pub realm: String,
+  /// OpenID Connect client ID
+  pub client_id: String,
+  /// Kerberos realm passed to `kinit`
+  pub kerberos_realm: String,
+}
+
+impl Default for SsoConfig {
+  fn default() -> Self {
+    SsoConfig {
+      url: "https://sso.csh.rit.edu/auth".to_string(),
+      realm: "csh".to_string(),
+      client_id: "clidrink".to_string(),
+      kerberos_realm: "CSH.RIT.EDU".to_string(),
+    }
+  }
+}
+
+impl SsoConfig {
+  fn openid_connect_url(&self, endpoint: &str) -> String {
+    format!(
+      "{}/realms/{}/protocol/openid-connect/{}",
+      self.url.trim_end_matches('/'),
+      self.realm,
+      endpoint
+    )
+  }
+  pub fn auth_url(&self) -> String {
+    format!(
+      "{}?client_id={}&redirect_uri=drink%3A%2F%2Fcallback&response_type=token%20id_token&scope=openid%20profile%20drink_balance&state=&nonce=",
+      self.openid_connect_url("auth"),
+      form_urlencoded::byte_serialize(self.client_id.as_bytes()).collect::<String>()
+    )
+  }
+  pub fn userinfo_url(&self) -> String {
+    self.openid_connect_url("userinfo")
+  }
+  pub fn token_url(&self) -> String {
+    self.openid_connect_url("token")
+  }
+  pub fn device_url(&self) -> String {
+    self.openid_connect_url("auth/device")
+  }
 }
 
 #[derive(Debug)]
 pub enum APIError {
-  Unauthorized,
-  BadFormat,
+  /// With what the auth provider suggests doing about it
+  Unauthorized(&'static str),
+  BadFormat(FormatError),
   HTTPError(http::Error),
   IsahcError(isahc::Error),
-  ServerError(Option<Uri>, String),
+  ServerError(Box<ServerError>),
   LoginAborted,
+  LoginFailed(LoginError),
+  DeviceLoginFailed(String),
+  NoTTY,
+  PasswordSourceFailed(String),
+  ItemNotFound(String),
+  AmbiguousItem(String),
+  Cancelled,
+  NeedsConfirmation,
+  UnknownSlot(String, u8),
+  MachineOffline(String),
+  SlotEmpty(String, u8),
+  SlotInactive(String, u8),
+  InsufficientCredits {
+    needed: i64,
+    have: i64,
+  },
+  /// The drop request failed partway through, so it may or may not have
+  /// happened. Holds what went wrong.
+  DropOutcomeUnknown(Box<APIError>),
+}
+
+/// A response that wasn't a 200 (or a 401/403, those are `Unauthorized`)
+#[derive(Debug)]
+pub struct ServerError {
+  pub status: StatusCode,
+  /// Where the request ended up, after following redirects
+  pub endpoint: Option<Uri>,
+  pub body: ServerErrorBody,
+}
+
+/// The body of an error response, parsed as well as we could
+#[derive(Debug, Clone)]
+pub enum ServerErrorBody {
+  /// `{"error": "..."}`, what the drink API sends
+  Error(String),
+  /// `{"message": "..."}`, what the drink API sends when Flask catches it
+  Message(String),
+  /// Some other JSON
+  Json(serde_json::Value),
+  /// Not JSON at all, probably an HTML page from a proxy
+  Text(String),
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -40,41 +564,149 @@ struct MessageResponse {
   message: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl ServerErrorBody {
+  fn parse(text: String) -> Self {
+    if let Ok(body) = serde_json::from_str::<ErrorResponse>(&text) {
+      ServerErrorBody::Error(body.error)
+    } else if let Ok(body) = serde_json::from_str::<MessageResponse>(&text) {
+      ServerErrorBody::Message(body.message)
+    } else if let Ok(value) = serde_json::from_str(&text) {
+      ServerErrorBody::Json(value)
+    } else {
+      ServerErrorBody::Text(text)
+    }
+  }
+
+  /// The part of the body worth showing to a user
+  pub fn message(&self) -> String {
+    match self {
+      ServerErrorBody::Error(message)
+      | ServerErrorBody::Message(message)
+      | ServerErrorBody::Text(message) => message.clone(),
+      ServerErrorBody::Json(value) => value.to_string(),
+    }
+  }
+}
+
+impl ServerError {
+  pub fn message(&self) -> String {
+    self.body.message()
+  }
+}
+
+/// Something the server (or SSO) sent that we couldn't make sense of
+#[derive(Debug)]
+pub struct FormatError {
+  /// What we were trying to read
+  pub context: String,
+  /// Where in the JSON things went wrong, like `machines[0].slots[2].count`
+  pub path: Option<String>,
+  pub source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl FormatError {
+  pub fn new(context: impl Into<String>) -> Self {
+    FormatError {
+      context: context.into(),
+      path: None,
+      source: None,
+    }
+  }
+
+  pub fn with_source(mut self, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+    self.source = Some(Box::new(source));
+    self
+  }
+
+  fn json(context: impl Into<String>, err: serde_path_to_error::Error<serde_json::Error>) -> Self {
+    let path = err.path().to_string();
+    FormatError {
+      context: context.into(),
+      // serde_path_to_error says "." when it failed before getting anywhere
+      path: Some(path).filter(|path| path != "."),
+      source: Some(Box::new(err.into_inner())),
+    }
+  }
+}
+
+impl fmt::Display for FormatError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.context)?;
+    if let Some(path) = &self.path {
+      write!(f, " (at {})", path)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for FormatError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    self
+      .source
+      .as_deref()
+      .map(|source| source as &(dyn std::error::Error + 'static))
+  }
+}
+
+/// What `GET /drinks` returns
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DrinkList {
   pub machines: Vec<Machine>,
   pub message: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Machine {
+  /// Something like "Big Drink"
   pub display_name: String,
   pub id: u64,
   pub is_online: bool,
+  /// Something like "bigdrink", this is what drops take
   pub name: String,
   pub slots: Vec<Slot>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Slot {
+  /// Disabled slots can't be dropped from
   pub active: bool,
+  /// How many are left, for machines that can count
   pub count: Option<u64>,
   pub empty: bool,
   pub item: Item,
+  /// `id` of the machine this slot is in
   pub machine: u64,
   pub number: u8,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Item {
   pub id: u64,
   pub name: String,
+  /// In credits
   pub price: u64,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct User {
-  preferred_username: String,
+/// The bits of the SSO userinfo response we care about
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+  /// CSH username
+  pub preferred_username: String,
+  /// Full name
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub groups: Vec<String>,
+}
+
+/// What [`DrinkClient::logout`] got rid of
+#[derive(Debug, Clone, Default)]
+pub struct Logout {
+  /// The credential cache whose ticket we destroyed
+  pub destroyed_ccache: Option<String>,
+  /// Set when we were asked to destroy the ticket but didn't, because clink
+  /// didn't put it there
+  pub kept_ccache: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -116,181 +748,806 @@ struct DropResponse {
   // message: String,
 }
 
-impl std::error::Error for APIError {}
+impl std::error::Error for APIError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      APIError::HTTPError(err) => Some(err),
+      APIError::IsahcError(err) => Some(err),
+      APIError::BadFormat(err) => Some(err),
+      APIError::LoginFailed(err) => Some(err),
+      APIError::DropOutcomeUnknown(err) => Some(err.as_ref()),
+      _ => None,
+    }
+  }
+}
 
-impl fmt::Display for APIError {
+/// Shows everything we know about an error, for bug reports. See
+/// [`APIError::verbose`].
+pub struct Verbose<'a>(&'a APIError);
+
+impl fmt::Display for Verbose<'_> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)?;
+    if let APIError::ServerError(err) = self.0 {
+      write!(f, "\n  Status: {}", err.status)?;
+      if let Some(endpoint) = &err.endpoint {
+        write!(f, "\n  Endpoint: {}", endpoint)?;
+      }
+      match &err.body {
+        ServerErrorBody::Error(error) => write!(f, "\n  Body: {{\"error\": {:?}}}", error)?,
+        ServerErrorBody::Message(message) => write!(f, "\n  Body: {{\"message\": {:?}}}", message)?,
+        ServerErrorBody::Json(value) => write!(f, "\n  Body: {}", value)?,
+        ServerErrorBody::Text(text) => write!(f, "\n  Body: {}", text.trim_end())?,
+      }
+    }
+    let mut source = std::error::Error::source(self.0);
+    while let Some(err) = source {
+      write!(f, "\n  Caused by: {}", err)?;
+      source = err.source();
+    }
+    Ok(())
+  }
+}
+
+impl APIError {
+  /// Whether trying again might work: the network flaked out, or a proxy in
+  /// front of the server couldn't reach it. Timeouts don't count, a server
+  /// that's hung would just keep us waiting that much longer.
+  pub fn is_transient(&self) -> bool {
     match self {
-      APIError::Unauthorized => write!(
-        f,
-        "Unauthorized (Did your Kerberos ticket expire?: `kinit`)"
+      APIError::IsahcError(err) => matches!(
+        err.kind(),
+        ErrorKind::ConnectionFailed | ErrorKind::NameResolution | ErrorKind::Io
+      ),
+      APIError::ServerError(err) => matches!(
+        err.status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+      ),
+      _ => false,
+    }
+  }
+
+  /// Whether the request could have reached the server even though it
+  /// failed. Only connecting (or TLS) going wrong means it definitely didn't.
+  fn may_have_been_sent(&self) -> bool {
+    match self {
+      APIError::IsahcError(err) => !matches!(
+        err.kind(),
+        ErrorKind::ConnectionFailed
+          | ErrorKind::NameResolution
+          | ErrorKind::BadServerCertificate
+          | ErrorKind::BadClientCertificate
+          | ErrorKind::TlsEngine
+          | ErrorKind::InvalidRequest
+          | ErrorKind::ClientInitialization
       ),
-      APIError::BadFormat => write!(f, "BadFormat (The server sent data we didn't understand)"),
-      APIError::ServerError(path, message) => write!(
+      APIError::ServerError(_) => self.is_transient(),
+      _ => false,
+    }
+  }
+
+  /// Renders the error along with its status, endpoint, server response and
+  /// whatever caused it, for `--verbose`
+  pub fn verbose(&self) -> Verbose<'_> {
+    Verbose(self)
+  }
+
+  /// What the process should exit with if this is why we're stopping, so
+  /// scripts can tell failures apart. Keep this in sync with the README.
+  ///
+  /// | Code | Meaning                                                  |
+  /// |------|----------------------------------------------------------|
+  /// | 0    | Success                                                  |
+  /// | 1    | Anything not listed here (including bad config files)    |
+  /// | 2    | Bad command line arguments                               |
+  /// | 3    | `Unauthorized`: the server didn't accept our token       |
+  /// | 4    | `LoginAborted`/`NoTTY`/`PasswordSourceFailed`: no password was given |
+  /// | 5    | `HTTPError`/`IsahcError`: couldn't talk to the server    |
+  /// | 6    | `BadFormat`: the server sent something we don't get      |
+  /// | 7    | `ServerError` with a 4xx status                          |
+  /// | 8    | `ServerError` with a 5xx (or otherwise odd) status       |
+  /// | 9    | `Cancelled`/`NeedsConfirmation`: the drop wasn't confirmed |
+  /// | 10   | `MachineOffline`                                         |
+  /// | 11   | `SlotEmpty`                                              |
+  /// | 12   | `SlotInactive`                                           |
+  /// | 13   | `InsufficientCredits`                                    |
+  /// | 14   | `ItemNotFound`/`UnknownSlot`: nothing to drop            |
+  /// | 15   | `AmbiguousItem`: more than one drink matched             |
+  /// | 16   | `LoginFailed`/`DeviceLoginFailed`: Kerberos wouldn't give us a ticket, or SSO a token |
+  /// | 17   | `DropOutcomeUnknown`: the drop may or may not have happened |
+  pub fn exit_code(&self) -> u8 {
+    match self {
+      APIError::Unauthorized(_) => 3,
+      APIError::LoginAborted | APIError::NoTTY | APIError::PasswordSourceFailed(_) => 4,
+      APIError::HTTPError(_) | APIError::IsahcError(_) => 5,
+      APIError::BadFormat(_) => 6,
+      APIError::ServerError(err) if err.status.is_client_error() => 7,
+      APIError::ServerError(_) => 8,
+      APIError::Cancelled | APIError::NeedsConfirmation => 9,
+      APIError::MachineOffline(_) => 10,
+      APIError::SlotEmpty(_, _) => 11,
+      APIError::SlotInactive(_, _) => 12,
+      APIError::InsufficientCredits { .. } => 13,
+      APIError::ItemNotFound(_) | APIError::UnknownSlot(_, _) => 14,
+      APIError::AmbiguousItem(_) => 15,
+      APIError::LoginFailed(_) | APIError::DeviceLoginFailed(_) => 16,
+      APIError::DropOutcomeUnknown(_) => 17,
+    }
+  }
+}
+
+/// Everything we know about a drop before we do it
+#[derive(Debug, Clone)]
+pub struct DropPlan {
+  pub machine: Machine,
+  pub slot: Slot,
+  /// Credits the user has right now
+  pub balance: i64,
+}
+
+impl DropPlan {
+  /// Finds `slot` in `machine` in a status response
+  pub fn new(drinks: &DrinkList, machine: &str, slot: u8, balance: i64) -> Result<Self, APIError> {
+    let unknown = || APIError::UnknownSlot(machine.to_string(), slot);
+    let status = drinks
+      .machines
+      .iter()
+      .find(|status| status.name == machine)
+      .ok_or_else(unknown)?;
+    let slot = status
+      .slots
+      .iter()
+      .find(|status| status.number == slot)
+      .ok_or_else(unknown)?;
+    Ok(DropPlan {
+      machine: status.clone(),
+      slot: slot.clone(),
+      balance,
+    })
+  }
+
+  /// Checks for everything we know would make the server refuse the drop
+  pub fn validate(&self) -> Result<(), APIError> {
+    let machine = &self.machine.display_name;
+    let slot = self.slot.number;
+    if !self.machine.is_online {
+      Err(APIError::MachineOffline(machine.clone()))
+    } else if !self.slot.active {
+      Err(APIError::SlotInactive(machine.clone(), slot))
+    } else if self.slot.empty || self.slot.count == Some(0) {
+      Err(APIError::SlotEmpty(machine.clone(), slot))
+    } else if self.balance < self.price() {
+      Err(APIError::InsufficientCredits {
+        needed: self.price(),
+        have: self.balance,
+      })
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn price(&self) -> i64 {
+    self.slot.item.price as i64
+  }
+
+  pub fn new_balance(&self) -> i64 {
+    self.balance - self.price()
+  }
+
+  /// Guesses whether a drop we lost track of (see
+  /// [`APIError::DropOutcomeUnknown`]) went through, from the balance now
+  pub fn describe_outcome(&self, balance: i64) -> String {
+    if balance == self.new_balance() {
+      format!(
+        "Your balance went from {} to {}, so it looks like it dropped",
+        self.balance, balance
+      )
+    } else if balance == self.balance {
+      format!(
+        "Your balance is still {}, so it looks like it didn't drop",
+        balance
+      )
+    } else {
+      format!(
+        "Your balance went from {} to {}, so something else changed it too",
+        self.balance, balance
+      )
+    }
+  }
+}
+
+/// How hard to try GET requests again when the network flakes out (see
+/// [`APIError::is_transient`]). Drops are never retried, since doing one
+/// twice costs twice.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  /// Tries after the first one
+  pub max_retries: u32,
+  /// Longest to wait before the first retry, doubled for each one after
+  pub initial_delay: Duration,
+  /// Longest to wait before any retry
+  pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_retries: 3,
+      initial_delay: Duration::from_millis(250),
+      max_delay: Duration::from_secs(4),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Never retries
+  pub fn none() -> Self {
+    RetryPolicy {
+      max_retries: 0,
+      ..RetryPolicy::default()
+    }
+  }
+
+  /// How long to wait before retry number `retry` (starting at 0): somewhere
+  /// between half and all of the backoff, so clients that failed together
+  /// don't all come back at once
+  fn delay(&self, retry: u32) -> Duration {
+    let backoff = self
+      .initial_delay
+      .saturating_mul(2u32.saturating_pow(retry))
+      .min(self.max_delay);
+    let half = backoff / 2;
+    half + half.mul_f64(fastrand::f64())
+  }
+}
+
+impl fmt::Display for APIError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      APIError::Unauthorized(hint) => write!(f, "Unauthorized ({})", hint),
+      APIError::BadFormat(_) => {
+        write!(f, "BadFormat (The server sent data we didn't understand)")
+      }
+      APIError::ServerError(err) => write!(
         f,
-        "ServerError for {}: {}",
-        match path {
+        "ServerError ({}) for {}: {}",
+        err.status,
+        match err.endpoint {
           Some(ref uri) => uri.to_string(),
           None => "<unknown>".to_string(),
         },
-        message
+        err.message()
       ),
       APIError::HTTPError(err) => write!(f, "HTTPError: {}", err),
       APIError::IsahcError(err) => write!(f, "IsahcError: {}", err),
-      APIError::LoginAborted => write!(f, "LoginAborted"),
+      APIError::LoginAborted => write!(f, "LoginAborted (No password was given)"),
+      APIError::LoginFailed(err) => write!(f, "LoginFailed ({})", err),
+      APIError::DeviceLoginFailed(message) => write!(f, "DeviceLoginFailed ({})", message),
+      APIError::NoTTY => write!(
+        f,
+        "NoTTY (There's no terminal to ask for your password on, use --password-stdin, --password-file, CLINK_PASSWORD_COMMAND or --keytab)"
+      ),
+      APIError::PasswordSourceFailed(message) => {
+        write!(f, "PasswordSourceFailed ({})", message)
+      }
+      APIError::ItemNotFound(query) => {
+        write!(f, "ItemNotFound (Nothing matches {:?})", query)
+      }
+      APIError::AmbiguousItem(query) => write!(
+        f,
+        "AmbiguousItem (More than one drink matches {:?}, try `clink drop <machine> <slot>`)",
+        query
+      ),
+      APIError::Cancelled => write!(f, "Cancelled"),
+      APIError::NeedsConfirmation => write!(
+        f,
+        "NeedsConfirmation (There's no terminal to ask on, pass --yes to drop anyway)"
+      ),
+      APIError::UnknownSlot(machine, slot) => {
+        write!(f, "UnknownSlot ({} doesn't have a slot {})", machine, slot)
+      }
+      APIError::MachineOffline(machine) => write!(f, "MachineOffline ({} is offline)", machine),
+      APIError::SlotEmpty(machine, slot) => {
+        write!(f, "SlotEmpty (Slot {} in {} is empty)", slot, machine)
+      }
+      APIError::SlotInactive(machine, slot) => {
+        write!(f, "SlotInactive (Slot {} in {} is disabled)", slot, machine)
+      }
+      APIError::DropOutcomeUnknown(err) => write!(
+        f,
+        "DropOutcomeUnknown (Lost track of the drop partway through, so it may or may not have happened. Check your balance with `clink credits` before trying again: {})",
+        err
+      ),
+      APIError::InsufficientCredits { needed, have } => write!(
+        f,
+        "InsufficientCredits (That costs {} credits, but you only have {})",
+        needed, have
+      ),
     }
   }
 }
 
-impl Default for API {
-  fn default() -> Self {
-    Self::new(
-      "https://drink.csh.rit.edu".to_string(),
-      Box::new(API::default_password_prompt),
-    )
-  }
-}
-
 enum APIBody<T: Serialize> {
   Json(T),
   NoBody,
 }
 
-type TryPasswordFn = dyn Fn(String) -> Result<PasswordResult, APIError> + Send + 'static;
-type PasswordFunction = dyn Fn(String, Box<TryPasswordFn>) + Send + 'static;
+/// A password someone typed into a file or pipe, minus the newline they
+/// typed after it
+fn trim_password(password: &str, from: &str) -> Result<String, APIError> {
+  let password = password.trim_end_matches(['\r', '\n']);
+  match password.is_empty() {
+    true => Err(APIError::PasswordSourceFailed(format!(
+      "Got an empty password from {}",
+      from
+    ))),
+    false => Ok(password.to_string()),
+  }
+}
+
+fn read_password_stdin() -> Result<String, APIError> {
+  let mut password = String::new();
+  io::stdin()
+    .read_to_string(&mut password)
+    .map_err(|err| APIError::PasswordSourceFailed(format!("Couldn't read stdin: {}", err)))?;
+  trim_password(&password, "stdin")
+}
+
+fn read_password_file(path: &Path) -> Result<String, APIError> {
+  let password = fs::read_to_string(path).map_err(|err| {
+    APIError::PasswordSourceFailed(format!("Couldn't read {}: {}", path.display(), err))
+  })?;
+  trim_password(&password, &path.display().to_string())
+}
+
+fn run_password_command(command: &str) -> Result<String, APIError> {
+  let output = Command::new("sh")
+    .arg("-c")
+    .arg(command)
+    .stdin(Stdio::null())
+    .stderr(Stdio::inherit())
+    .output()
+    .map_err(|err| {
+      APIError::PasswordSourceFailed(format!("Couldn't run `{}`: {}", command, err))
+    })?;
+  if !output.status.success() {
+    return Err(APIError::PasswordSourceFailed(format!(
+      "`{}` failed ({})",
+      command, output.status
+    )));
+  }
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  trim_password(
+    stdout.lines().next().unwrap_or(""),
+    &format!("`{}`", command),
+  )
+}
+
+/// Tries logging in with a password
+pub type TryPasswordFn = dyn Fn(String) -> Result<PasswordResult, APIError> + Send + 'static;
+/// Asks for passwords for a username, handing each to the [`TryPasswordFn`]
+/// until one works or the user gives up. Giving up is returning without a
+/// successful try.
+pub type PasswordFunction = dyn Fn(String, Box<TryPasswordFn>) + Send + 'static;
 
+/// How a login attempt went, `message` is what `kinit` had to say about it
 pub struct PasswordResult {
   pub message: String,
   pub success: bool,
 }
 
-impl<T: Serialize> From<APIBody<T>> for isahc::Body {
-  fn from(body: APIBody<T>) -> Self {
+impl<T: Serialize> From<&APIBody<T>> for isahc::Body {
+  fn from(body: &APIBody<T>) -> Self {
     match body {
-      APIBody::Json(value) => serde_json::to_string(&value).unwrap().into(),
+      APIBody::Json(value) => serde_json::to_string(value).unwrap().into(),
       APIBody::NoBody => ().into(),
     }
   }
 }
 
-impl Clone for API {
+impl Clone for DrinkClient {
   fn clone(&self) -> Self {
     Self {
       token: Arc::clone(&self.token),
+      token_cache: self.token_cache.clone(),
       api_base_url: self.api_base_url.clone(),
+      auth: Arc::clone(&self.auth),
+      sso: self.sso.clone(),
+      client: self.client.clone(),
+      proxy: self.proxy.clone(),
+      timeout: self.timeout,
+      connect_timeout: self.connect_timeout,
+      retry: self.retry.clone(),
+      username: self.username.clone(),
+      password_source: self.password_source.clone(),
       password_function: Arc::clone(&self.password_function),
     }
   }
 }
 
-impl API {
-  pub fn new(api_base_url: String, password_function: Box<PasswordFunction>) -> API {
-    // We should find a way to spin this off in a thread
-    // api.get_token().ok();
-    API {
+impl DrinkClient {
+  pub fn builder() -> DrinkClientBuilder {
+    DrinkClientBuilder::default()
+  }
+
+  pub fn base_url(&self) -> &str {
+    &self.api_base_url
+  }
+
+  pub fn sso(&self) -> &SsoConfig {
+    &self.sso
+  }
+
+  pub fn auth(&self) -> &dyn AuthProvider {
+    self.auth.as_ref()
+  }
+
+  /// A copy of this client, down to the HTTP and retry settings, that gets
+  /// its tokens from `auth` instead. It starts without a token of its own.
+  pub fn with_auth(&self, auth: impl AuthProvider + 'static) -> DrinkClient {
+    DrinkClient {
       token: Arc::new(Mutex::new(None)),
-      api_base_url,
-      password_function: Arc::new(Mutex::new(password_function)),
+      auth: Arc::new(auth),
+      ..self.clone()
+    }
+  }
+
+  /// The client every request goes through, for making requests of your own
+  /// with the same settings
+  pub fn http_client(&self) -> &HttpClient {
+    &self.client
+  }
+
+  /// The proxy from [`DrinkClientBuilder::proxy`]. curl can still find one
+  /// in `https_proxy` and friends when this is `None`.
+  pub fn proxy(&self) -> Option<&Uri> {
+    self.proxy.as_ref()
+  }
+
+  /// Applies our timeouts to a request, since the client might not be one we
+  /// made
+  fn configure(&self, mut builder: http::request::Builder) -> http::request::Builder {
+    if let Some(timeout) = self.timeout {
+      builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = self.connect_timeout {
+      builder = builder.connect_timeout(timeout);
+    }
+    builder
+  }
+
+  /// Sends a request with our bearer token. If the server doesn't like the
+  /// token, we throw it out, grab a new one (logging in again if we have to),
+  /// and give it one more shot. GETs also get tried again when the network
+  /// acts up, see [`RetryPolicy`].
+  fn authenticated_request<O, I, F>(&self, builder: F, input: APIBody<I>) -> Result<O, APIError>
+  where
+    I: Serialize,
+    O: de::DeserializeOwned,
+    F: Fn() -> http::request::Builder,
+  {
+    let idempotent = builder().method_ref() == Some(&http::Method::GET);
+    let mut retry = 0;
+    loop {
+      let result = match self.send_authenticated(builder(), &input) {
+        // A static token won't be any better the second time
+        Err(APIError::Unauthorized(_)) if self.auth.can_refresh() => {
+          self.send_authenticated(builder(), &input)
+        }
+        result => result,
+      };
+      match result {
+        Err(err) if idempotent && err.is_transient() && retry < self.retry.max_retries => {
+          thread::sleep(self.retry.delay(retry));
+          retry += 1;
+        }
+        result => return result,
+      }
     }
   }
-  fn authenticated_request<O, I>(
+  fn send_authenticated<O, I>(
     &self,
     builder: http::request::Builder,
-    input: APIBody<I>,
+    input: &APIBody<I>,
   ) -> Result<O, APIError>
   where
     I: Serialize,
     O: de::DeserializeOwned,
   {
-    let client = HttpClient::new().map_err(APIError::IsahcError)?;
     let token = self.get_token()?;
-    let builder = builder
+    let builder = self
+      .configure(builder)
       .header("Authorization", token)
       .header("Accept", "application/json");
     let builder = match input {
       APIBody::Json(_) => builder.header("Content-Type", "application/json"),
       APIBody::NoBody => builder,
     };
-    let mut response = client
+    let mut response = self
+      .client
       .send(builder.body(input).map_err(APIError::HTTPError)?)
       .map_err(APIError::IsahcError)?;
+    let endpoint = response
+      .effective_uri()
+      .map(|uri| uri.to_string())
+      .unwrap_or_else(|| "<unknown>".to_string());
     match response.status() {
-      StatusCode::OK => match response.json::<O>() {
-        Ok(value) => Ok(value),
-        Err(_) => Err(APIError::BadFormat),
-      },
+      StatusCode::OK => {
+        let text = response.text().map_err(|err| {
+          APIError::BadFormat(
+            FormatError::new(format!("Couldn't read the response from {}", endpoint))
+              .with_source(err),
+          )
+        })?;
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&text)).map_err(
+          |err| {
+            APIError::BadFormat(FormatError::json(
+              format!("Couldn't parse the response from {}", endpoint),
+              err,
+            ))
+          },
+        )
+      }
+      StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+        // Whatever we have is no good, make sure we don't try it again
+        self.invalidate_token();
+        Err(APIError::Unauthorized(self.auth.unauthorized_hint()))
+      }
       _ => {
-        let text = response.text().map_err(|_| APIError::BadFormat)?;
-        let text_ref = &text;
-        Err(APIError::ServerError(
-          response.effective_uri().cloned(),
-          serde_json::from_str::<ErrorResponse>(&text)
-            .map(|body| body.error)
-            .or_else(move |_| {
-              serde_json::from_str::<MessageResponse>(text_ref).map(|body| body.message)
-            })
-            .unwrap_or(text),
-        ))
+        let text = response.text().map_err(|err| {
+          APIError::BadFormat(
+            FormatError::new(format!("Couldn't read the response from {}", endpoint))
+              .with_source(err),
+          )
+        })?;
+        Err(APIError::ServerError(Box::new(ServerError {
+          status: response.status(),
+          endpoint: response.effective_uri().cloned(),
+          body: ServerErrorBody::parse(text),
+        })))
       }
     }
   }
+  /// Drops whatever is in `slot` of `machine`, returning the new balance.
+  /// This doesn't check anything first, see [`DropPlan`] for that. It's never
+  /// retried: if it fails after it might have reached the server, you get
+  /// [`APIError::DropOutcomeUnknown`].
   pub fn drop(&self, machine: String, slot: u8) -> Result<i64, APIError> {
+    // Get logging in out of the way, so its errors aren't mistaken for the
+    // drop's
+    self.get_token()?;
     self
-      .authenticated_request::<DropResponse, _>(
-        Request::post(format!("{}/drinks/drop", self.api_base_url)),
+      .authenticated_request::<DropResponse, _, _>(
+        || Request::post(format!("{}/drinks/drop", self.api_base_url)),
         APIBody::Json(DropRequest { machine, slot }),
       )
       .map(|drop| drop.drinkBalance)
+      .map_err(|err| match err.may_have_been_sent() {
+        true => APIError::DropOutcomeUnknown(Box::new(err)),
+        false => err,
+      })
   }
 
-  fn take_token(&self, token: &mut Option<String>) -> Result<String, APIError> {
-    match token {
-      Some(token) => Ok(token.to_string()),
-      None => {
-        let response = Request::get("https://sso.csh.rit.edu/auth/realms/csh/protocol/openid-connect/auth?client_id=clidrink&redirect_uri=drink%3A%2F%2Fcallback&response_type=token%20id_token&scope=openid%20profile%20drink_balance&state=&nonce=")
-          .authentication(Authentication::negotiate())
-          .body(()).map_err(APIError::HTTPError)?.send().map_err(APIError::IsahcError)?;
-        let location = match response.headers().get("Location") {
-          Some(location) => location,
-          None => {
-            self.login()?;
-            return self.take_token(token);
-          }
-        };
-        let url = Url::parse(
-          &location
-            .to_str()
-            .map_err(|_| APIError::BadFormat)?
-            .replace('#', "?"),
-        )
-        .map_err(|_| APIError::BadFormat)?;
+  fn take_token(&self, token: &mut Option<Token>) -> Result<String, APIError> {
+    match self.reuse_token(token) {
+      Some(reused) => Ok(reused.value),
+      None => self.fetch_token(token),
+    }
+  }
 
-        for (key, value) in url.query_pairs() {
-          if key == "access_token" {
-            let value = format!("Bearer {}", value);
-            *token = Some(value.clone());
-            return Ok(value);
-          }
-        }
-        Err(APIError::BadFormat)
+  /// A token we already have, from memory, the token cache, or a refresh
+  /// token, without asking the auth provider for a new one
+  fn reuse_token(&self, token: &mut Option<Token>) -> Option<Token> {
+    if let Some(cached) = token.as_ref().filter(|cached| cached.is_fresh()) {
+      return Some(cached.clone());
+    }
+    let cached = self.token_cache.load();
+    if let Some(cached) = cached.as_ref().filter(|cached| cached.is_fresh()) {
+      *token = Some(cached.clone());
+      return Some(cached.clone());
+    }
+    // A stale token might still have a refresh token that's good, which saves
+    // us from logging in again
+    let refresh_token = token
+      .as_ref()
+      .or(cached.as_ref())
+      .and_then(|stale| stale.refresh_token.clone());
+    let new_token = refresh_token.and_then(|refresh_token| self.refresh(&refresh_token).ok())?;
+    self.keep_token(token, new_token.clone());
+    Some(new_token)
+  }
+
+  fn fetch_token(&self, token: &mut Option<Token>) -> Result<String, APIError> {
+    let new_token = self.auth.token(self)?;
+    Ok(self.keep_token(token, new_token))
+  }
+
+  /// Makes `new_token` the one we use, returning its value
+  fn keep_token(&self, token: &mut Option<Token>, new_token: Token) -> String {
+    let value = new_token.value.clone();
+    // The cache is just an optimization, we can live without it
+    self.token_cache.store(&new_token).ok();
+    *token = Some(new_token);
+    value
+  }
+
+  /// Trades a refresh token for a new token at SSO. The new token holds on to
+  /// the refresh token SSO rotated to, or this one if it didn't.
+  fn refresh(&self, refresh_token: &str) -> Result<Token, APIError> {
+    let response = self.post_form::<TokenResponse>(
+      self.sso.token_url(),
+      &[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+      ],
+      "token",
+    )?;
+    match response {
+      Ok(response) => {
+        let mut token = response.into_token();
+        token
+          .refresh_token
+          .get_or_insert_with(|| refresh_token.to_string());
+        Ok(token)
+      }
+      // invalid_grant, the refresh token expired or was revoked
+      Err(_) => Err(APIError::Unauthorized(self.auth.unauthorized_hint())),
+    }
+  }
+
+  /// POSTs a form (with our client ID added) to SSO. The inner error is
+  /// SSO telling us no, anything else that isn't a 200 is a
+  /// [`ServerError`].
+  fn post_form<T: de::DeserializeOwned>(
+    &self,
+    url: String,
+    params: &[(&str, &str)],
+    what: &str,
+  ) -> Result<Result<T, OAuthError>, APIError> {
+    let body = form_urlencoded::Serializer::new(String::new())
+      .append_pair("client_id", &self.sso.client_id)
+      .extend_pairs(params)
+      .finish();
+    let request = self
+      .configure(Request::post(url))
+      .header("Content-Type", "application/x-www-form-urlencoded")
+      .header("Accept", "application/json")
+      .body(body)
+      .map_err(APIError::HTTPError)?;
+    let mut response = self.client.send(request).map_err(APIError::IsahcError)?;
+    let text = response.text().map_err(|err| {
+      APIError::BadFormat(FormatError::new("Couldn't read the response from SSO").with_source(err))
+    })?;
+    match response.status() {
+      StatusCode::OK => {
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(&text))
+          .map(Ok)
+          .map_err(|err| {
+            APIError::BadFormat(FormatError::json(
+              format!("Couldn't parse the {} from SSO", what),
+              err,
+            ))
+          })
+      }
+      status => match serde_json::from_str::<OAuthError>(&text) {
+        Ok(err) if status.is_client_error() => Ok(Err(err)),
+        _ => Err(APIError::ServerError(Box::new(ServerError {
+          status,
+          endpoint: response.effective_uri().cloned(),
+          body: ServerErrorBody::parse(text),
+        }))),
+      },
+    }
+  }
+
+  /// Asks SSO for a token over SPNEGO, without touching the cache or logging
+  /// in. `None` means SSO didn't redirect us, which happens when we don't have
+  /// a Kerberos ticket (or it's expired).
+  pub fn request_token(&self) -> Result<Option<Token>, APIError> {
+    let request = self
+      .configure(Request::get(self.sso.auth_url()))
+      .authentication(Authentication::negotiate())
+      .body(())
+      .map_err(APIError::HTTPError)?;
+    let response = self.client.send(request).map_err(APIError::IsahcError)?;
+    let location = match response.headers().get("Location") {
+      Some(location) => location,
+      None => return Ok(None),
+    };
+    let location = location.to_str().map_err(|err| {
+      APIError::BadFormat(
+        FormatError::new("SSO sent a Location header that isn't text").with_source(err),
+      )
+    })?;
+    let url = Url::parse(&location.replace('#', "?")).map_err(|err| {
+      APIError::BadFormat(
+        FormatError::new(format!("SSO redirected us to a bad URL: {}", location)).with_source(err),
+      )
+    })?;
+
+    let mut access_token = None;
+    let mut expires_in = None;
+    for (key, value) in url.query_pairs() {
+      match key.as_ref() {
+        "access_token" => access_token = Some(format!("Bearer {}", value)),
+        "expires_in" => expires_in = value.parse::<u64>().ok(),
+        _ => {}
       }
     }
+    let value = access_token.ok_or_else(|| {
+      APIError::BadFormat(FormatError::new(format!(
+        "SSO redirected us to {} without an access_token",
+        url
+      )))
+    })?;
+    Ok(Some(Token::new(value, expires_in)))
   }
 
+  /// The `Authorization` header value we'd send right now, getting a new
+  /// token if we need to
   pub fn get_token(&self) -> Result<String, APIError> {
+    if !self.auth.can_refresh() {
+      return self.auth.token(self).map(|token| token.value);
+    }
     let mut token = self.token.lock().unwrap();
     self.take_token(token.deref_mut())
   }
 
+  /// Like [`DrinkClient::get_token`], but never logs in or asks for a
+  /// password. `None` means we'd have to.
+  pub fn try_get_token(&self) -> Result<Option<Token>, APIError> {
+    if !self.auth.can_refresh() {
+      return self.auth.try_token(self);
+    }
+    let mut token = self.token.lock().unwrap();
+    if let Some(reused) = self.reuse_token(token.deref_mut()) {
+      return Ok(Some(reused));
+    }
+    let new_token = self.auth.try_token(self)?;
+    if let Some(new_token) = &new_token {
+      self.keep_token(token.deref_mut(), new_token.clone());
+    }
+    Ok(new_token)
+  }
+
+  /// Forgets the current token, both in memory and on disk. Does nothing
+  /// with a [`StaticToken`], there's nothing else we could use.
+  pub fn invalidate_token(&self) {
+    if !self.auth.can_refresh() {
+      return;
+    }
+    let mut token = self.token.lock().unwrap();
+    // Hold on to the refresh token, it might still be good for a new token
+    let refresh_token = token
+      .take()
+      .or_else(|| self.token_cache.load())
+      .and_then(|stale| stale.refresh_token);
+    self.token_cache.clear().ok();
+    if let Some(refresh_token) = refresh_token {
+      let stale = Token {
+        value: String::new(),
+        expires_at: Some(0),
+        refresh_token: Some(refresh_token),
+      };
+      self.token_cache.store(&stale).ok();
+      *token = Some(stale);
+    }
+  }
+
+  /// Asks for the password on the terminal
   pub fn default_password_prompt(username: String, try_password: Box<TryPasswordFn>) {
     loop {
-      let password = prompt_password(format!("Password for {username}: ")).unwrap();
+      let password = match prompt_password(format!("Password for {username}: ")) {
+        Ok(password) => password,
+        // No terminal to ask on
+        Err(_) => return,
+      };
       match (try_password)(password) {
         Ok(PasswordResult {
           success: false,
           message,
         }) => {
-          print!("Login failed: {message}");
+          eprintln!("Login failed: {message}");
         }
         Ok(PasswordResult {
           success: true,
@@ -305,82 +1562,236 @@ impl API {
     }
   }
 
+  /// Replaces the password prompt, for when the terminal isn't ours (like in
+  /// the TUI)
   pub fn set_password_prompt(&mut self, prompt: Box<PasswordFunction>) {
-    self.password_function = Arc::new(Mutex::new(prompt));
+    self.password_function = Arc::new(Mutex::new(Some(prompt)));
+  }
+
+  /// Throws out the current token and gets a new one. With `force_password`,
+  /// asks for the password and gets a new Kerberos ticket first, even if the
+  /// one we have still works (when the provider uses Kerberos at all).
+  pub fn login(&self, force_password: bool) -> Result<User, APIError> {
+    self.invalidate_token();
+    if force_password && self.auth.uses_kerberos() {
+      self.get_ticket()?;
+    }
+    self.get_user_info()
+  }
+
+  /// Logs in with the OAuth device flow, for when there's no Kerberos: gets a
+  /// code from SSO, hands it to `show` so the user can go approve it in a
+  /// browser, and waits until they do. The token and refresh token go in the
+  /// token cache, so later runs get new tokens without logging in again.
+  pub fn login_device(&self, show: impl FnOnce(&DeviceCode)) -> Result<User, APIError> {
+    let code = self
+      .post_form::<DeviceCode>(
+        self.sso.device_url(),
+        &[("scope", "openid profile drink_balance")],
+        "device code",
+      )?
+      .map_err(|err| APIError::DeviceLoginFailed(err.to_string()))?;
+    show(&code);
+
+    let expired = || APIError::DeviceLoginFailed("The code expired before it was approved".into());
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = code.interval;
+    loop {
+      thread::sleep(Duration::from_secs(interval));
+      if Instant::now() >= deadline {
+        return Err(expired());
+      }
+      let response = self.post_form::<TokenResponse>(
+        self.sso.token_url(),
+        &[
+          ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+          ("device_code", &code.device_code),
+        ],
+        "token",
+      )?;
+      match response {
+        Ok(response) => {
+          self.keep_token(&mut self.token.lock().unwrap(), response.into_token());
+          return self.get_user_info();
+        }
+        Err(err) => match err.error.as_str() {
+          "authorization_pending" => {}
+          "slow_down" => interval += 5,
+          "expired_token" => return Err(expired()),
+          "access_denied" => {
+            return Err(APIError::DeviceLoginFailed(
+              "The login was denied in the browser".into(),
+            ))
+          }
+          _ => return Err(APIError::DeviceLoginFailed(err.to_string())),
+        },
+      }
+    }
   }
 
-  fn login(&self) -> Result<(), APIError> {
+  /// Forgets our token. With `destroy_ticket`, also destroys the Kerberos
+  /// ticket, but only if clink is the one that got it.
+  pub fn logout(&self, destroy_ticket: bool) -> Result<Logout, APIError> {
+    *self.token.lock().unwrap() = None;
+    self.token_cache.clear().ok();
+    let mut logout = Logout::default();
+    if !destroy_ticket {
+      return Ok(logout);
+    }
+    let current = kerberos::ccache_name();
+    if current.is_some() && current == self.token_cache.remembered_ccache() {
+      kerberos::destroy().map_err(APIError::LoginFailed)?;
+      self.token_cache.forget_ccache().ok();
+      logout.destroyed_ccache = current;
+    } else {
+      logout.kept_ccache = current;
+    }
+    Ok(logout)
+  }
+
+  /// When our token expires, in seconds since the unix epoch, if SSO said
+  pub fn token_expires_at(&self) -> Option<u64> {
+    match &*self.token.lock().unwrap() {
+      Some(token) => token.expires_at,
+      None => self.token_cache.load().and_then(|token| token.expires_at),
+    }
+  }
+
+  /// When the Kerberos ticket expires, in seconds since the unix epoch
+  pub fn ticket_expires_at(&self) -> Option<u64> {
+    if !self.auth.uses_kerberos() {
+      return None;
+    }
+    kerberos::ticket().and_then(|ticket| ticket.expires_at)
+  }
+
+  /// Asks for the password until Kerberos gives us a ticket
+  fn get_ticket(&self) -> Result<(), APIError> {
     // Get credentials
-    let username: String = std::env::var("CLINK_USERNAME")
-      .ok()
+    let username: String = self
+      .username
+      .clone()
+      .or_else(|| std::env::var("CLINK_USERNAME").ok())
       .or_else(|| get_current_username().and_then(|username| username.into_string().ok()))
       .or_else(|| std::env::var("USER").ok())
       .expect("Couldn't determine username");
 
+    let principal = format!("{}@{}", username, self.sso.kerberos_realm);
+    let result = match &self.password_source {
+      PasswordSource::Prompt => return self.prompt_for_ticket(username, principal),
+      PasswordSource::Stdin => kerberos::login(&principal, &read_password_stdin()?),
+      PasswordSource::File(path) => kerberos::login(&principal, &read_password_file(path)?),
+      PasswordSource::Command(command) => {
+        kerberos::login(&principal, &run_password_command(command)?)
+      }
+      PasswordSource::Keytab(path) => {
+        // Otherwise Kerberos goes looking for a KDC first, and complains
+        // about that instead
+        if !path.is_file() {
+          return Err(APIError::PasswordSourceFailed(format!(
+            "There's no keytab at {}",
+            path.display()
+          )));
+        }
+        kerberos::login_keytab(&principal, path)
+      }
+    };
+    result.map_err(APIError::LoginFailed)?;
+    self.remember_ccache();
+    Ok(())
+  }
+
+  /// Asks for the password until it's right, or the prompt gives up
+  fn prompt_for_ticket(&self, username: String, principal: String) -> Result<(), APIError> {
     let password_function = self.password_function.lock().unwrap();
+    // rpassword asks on the controlling terminal, so there has to be one
+    if password_function.is_none() && File::open("/dev/tty").is_err() {
+      return Err(APIError::NoTTY);
+    }
     let (tx_password, rx_password) = channel();
-    // Get password
-    (password_function)(
-      username.clone(),
-      Box::new(move |password| {
-        // Start kinit, ready to get password from pipe
-        let mut process = Command::new("kinit")
-          .arg(format!("{}@CSH.RIT.EDU", username))
-          .stdin(Stdio::piped())
-          .stdout(Stdio::null())
-          .stderr(Stdio::piped())
-          .spawn()
-          .unwrap();
-        process
-          .stdin
-          .as_ref()
-          .unwrap()
-          .write_all(password.as_bytes())
-          .unwrap();
-        let success = process.wait().unwrap().success();
-        if success {
-          tx_password.send(()).unwrap();
-        }
-        let mut output = "".to_string();
-        process.stderr.unwrap().read_to_string(&mut output).unwrap();
-        Ok(PasswordResult {
-          success,
-          message: output,
-        })
-      }),
-    );
-    match rx_password.recv() {
-      Ok(_) => Ok(()),
-      Err(_) => Err(APIError::LoginAborted),
+    // Anything asking for the password again won't fix
+    let failure = Arc::new(Mutex::new(None));
+    let try_password: Box<TryPasswordFn> = {
+      let failure = Arc::clone(&failure);
+      Box::new(
+        move |password| match kerberos::login(&principal, &password) {
+          Ok(()) => {
+            tx_password.send(()).ok();
+            Ok(PasswordResult {
+              success: true,
+              message: String::new(),
+            })
+          }
+          Err(err) if err.can_retry() => Ok(PasswordResult {
+            success: false,
+            message: err.to_string(),
+          }),
+          Err(err) => {
+            *failure.lock().unwrap() = Some(err.clone());
+            Err(APIError::LoginFailed(err))
+          }
+        },
+      )
+    };
+    match password_function.as_ref() {
+      Some(password_function) => (password_function)(username, try_password),
+      None => DrinkClient::default_password_prompt(username, try_password),
+    }
+    match rx_password.try_recv() {
+      Ok(_) => {
+        self.remember_ccache();
+        Ok(())
+      }
+      Err(_) => match failure.lock().unwrap().take() {
+        Some(err) => Err(APIError::LoginFailed(err)),
+        None => Err(APIError::LoginAborted),
+      },
     }
   }
 
-  pub fn get_credits(&self) -> Result<i64, APIError> {
-    // Can also be used to get other user information
-    let user: User = self.authenticated_request(
-      Request::get("https://sso.csh.rit.edu/auth/realms/csh/protocol/openid-connect/userinfo"),
+  /// Notes that the ticket in the default credential cache is ours, so
+  /// `logout` can destroy it
+  fn remember_ccache(&self) {
+    if let Some(name) = kerberos::ccache_name() {
+      self.token_cache.remember_ccache(&name).ok();
+    }
+  }
+
+  /// Who our token belongs to
+  pub fn get_user_info(&self) -> Result<User, APIError> {
+    let userinfo_url = self.sso.userinfo_url();
+    self.authenticated_request(
+      || Request::get(&userinfo_url),
       APIBody::NoBody as APIBody<serde_json::Value>,
-    )?;
+    )
+  }
+
+  /// The user's drink credit balance
+  pub fn get_credits(&self) -> Result<i64, APIError> {
+    let user = self.get_user_info()?;
+    let url = format!(
+      "{}/users/credits?uid={}",
+      self.api_base_url, user.preferred_username
+    );
     let credit_response: CreditResponse = self.authenticated_request(
-      Request::get(format!(
-        "{}/users/credits?uid={}",
-        self.api_base_url, user.preferred_username
-      )),
+      || Request::get(&url),
       APIBody::NoBody as APIBody<serde_json::Value>,
     )?;
     Ok(credit_response.user.drinkBalance)
   }
 
+  /// Status of one machine, or all of them if `machine` is `None`
   pub fn get_status_for_machine(&self, machine: Option<&str>) -> Result<DrinkList, APIError> {
+    let url = format!(
+      "{}/drinks{}",
+      self.api_base_url,
+      match machine {
+        Some(machine) => format!("?machine={}", machine),
+        None => "".to_string(),
+      }
+    );
     self.authenticated_request(
-      Request::get(format!(
-        "{}/drinks{}",
-        self.api_base_url,
-        match machine {
-          Some(machine) => format!("?machine={}", machine),
-          None => "".to_string(),
-        }
-      )),
+      || Request::get(&url),
       APIBody::NoBody as APIBody<serde_json::Value>,
     )
   }
@@ -0,0 +1,231 @@
+use super::DrinkBackend;
+use crate::api::{APIError, DrinkList, Item, Machine, ServerError, ServerErrorBody, Slot, User};
+use http::{StatusCode, Uri};
+use std::sync::{Arc, Mutex};
+
+/// A drink server that only exists in memory. Drops take credits and stock
+/// away like the real one does, so a test can drop something and then see the
+/// result in `list`. Clones share the same state.
+#[derive(Clone)]
+pub struct MemoryBackend {
+  state: Arc<Mutex<State>>,
+}
+
+struct State {
+  drinks: DrinkList,
+  credits: i64,
+  user: User,
+}
+
+impl MemoryBackend {
+  pub fn new(drinks: DrinkList, credits: i64) -> Self {
+    MemoryBackend {
+      state: Arc::new(Mutex::new(State {
+        drinks,
+        credits,
+        user: User {
+          preferred_username: "user".to_string(),
+          name: None,
+          groups: Vec::new(),
+        },
+      })),
+    }
+  }
+
+  /// Two machines, one of them offline, and enough credits for a few drinks
+  pub fn sample() -> Self {
+    let slot =
+      |machine: u64, number: u8, id: u64, name: &str, price: u64, count: Option<u64>| Slot {
+        active: true,
+        count,
+        empty: count == Some(0),
+        item: Item {
+          id,
+          name: name.to_string(),
+          price,
+        },
+        machine,
+        number,
+      };
+    MemoryBackend::new(
+      DrinkList {
+        machines: vec![
+          Machine {
+            display_name: "Big Drink".to_string(),
+            id: 1,
+            is_online: true,
+            name: "bigdrink".to_string(),
+            slots: vec![
+              slot(1, 1, 1, "Coke", 50, None),
+              slot(1, 2, 2, "Sprite", 50, None),
+              slot(1, 3, 3, "Dr. Pepper", 75, Some(0)),
+            ],
+          },
+          Machine {
+            display_name: "Little Drink".to_string(),
+            id: 2,
+            is_online: false,
+            name: "littledrink".to_string(),
+            slots: vec![slot(2, 1, 4, "Coke Zero", 40, Some(3))],
+          },
+        ],
+        message: "Successfully retrieved machine contents".to_string(),
+      },
+      200,
+    )
+  }
+
+  pub fn with_username(self, username: &str) -> Self {
+    self.state.lock().unwrap().user.preferred_username = username.to_string();
+    self
+  }
+
+  /// Balance right now, without going through the [`DrinkBackend`] trait
+  pub fn credits(&self) -> i64 {
+    self.state.lock().unwrap().credits
+  }
+
+  /// Every machine right now, without going through the [`DrinkBackend`] trait
+  pub fn drinks(&self) -> DrinkList {
+    self.state.lock().unwrap().drinks.clone()
+  }
+}
+
+/// What the drink API sends back when it doesn't like a request
+fn server_error(status: StatusCode, endpoint: &'static str, message: &str) -> APIError {
+  APIError::ServerError(Box::new(ServerError {
+    status,
+    endpoint: Some(Uri::from_static(endpoint)),
+    body: ServerErrorBody::Error(message.to_string()),
+  }))
+}
+
+impl DrinkBackend for MemoryBackend {
+  fn get_status_for_machine(&self, machine: Option<&str>) -> Result<DrinkList, APIError> {
+    let state = self.state.lock().unwrap();
+    let mut drinks = state.drinks.clone();
+    if let Some(name) = machine {
+      drinks.machines.retain(|machine| machine.name == name);
+      if drinks.machines.is_empty() {
+        return Err(server_error(
+          StatusCode::BAD_REQUEST,
+          "/drinks",
+          "Invalid machine name",
+        ));
+      }
+    }
+    Ok(drinks)
+  }
+
+  fn get_credits(&self) -> Result<i64, APIError> {
+    Ok(self.credits())
+  }
+
+  fn drop(&self, machine: String, slot: u8) -> Result<i64, APIError> {
+    let mut state = self.state.lock().unwrap();
+    let credits = state.credits;
+    let machine = state
+      .drinks
+      .machines
+      .iter_mut()
+      .find(|status| status.name == machine)
+      .ok_or_else(|| {
+        server_error(
+          StatusCode::BAD_REQUEST,
+          "/drinks/drop",
+          "Invalid machine name",
+        )
+      })?;
+    if !machine.is_online {
+      return Err(server_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "/drinks/drop",
+        "Machine is offline",
+      ));
+    }
+    let slot = machine
+      .slots
+      .iter_mut()
+      .find(|status| status.number == slot)
+      .ok_or_else(|| server_error(StatusCode::BAD_REQUEST, "/drinks/drop", "Invalid slot"))?;
+    if !slot.active || slot.empty || slot.count == Some(0) {
+      return Err(server_error(
+        StatusCode::BAD_REQUEST,
+        "/drinks/drop",
+        "Slot is empty or disabled",
+      ));
+    }
+    let price = slot.item.price as i64;
+    if credits < price {
+      return Err(server_error(
+        StatusCode::PAYMENT_REQUIRED,
+        "/drinks/drop",
+        "Not enough credits",
+      ));
+    }
+    if let Some(count) = slot.count.as_mut() {
+      *count -= 1;
+      slot.empty = *count == 0;
+    }
+    state.credits -= price;
+    Ok(state.credits)
+  }
+
+  fn get_user_info(&self) -> Result<User, APIError> {
+    Ok(self.state.lock().unwrap().user.clone())
+  }
+
+  fn get_token(&self) -> Result<String, APIError> {
+    Ok("Bearer memory".to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn drop_takes_credits_and_stock() {
+    let backend = MemoryBackend::new(MemoryBackend::sample().drinks(), 100);
+    {
+      let mut state = backend.state.lock().unwrap();
+      state.drinks.machines[1].is_online = true;
+    }
+    assert_eq!(backend.drop("littledrink".to_string(), 1).unwrap(), 60);
+    assert_eq!(backend.drop("littledrink".to_string(), 1).unwrap(), 20);
+    let slot = &backend.drinks().machines[1].slots[0];
+    assert_eq!(slot.count, Some(1));
+    assert!(!slot.empty);
+  }
+
+  #[test]
+  fn drop_refuses_like_the_server() {
+    let backend = MemoryBackend::sample();
+    let status = |err: APIError| match err {
+      APIError::ServerError(err) => err.status,
+      err => panic!("expected a ServerError, got {}", err),
+    };
+    let drop = |machine: &str, slot| backend.drop(machine.to_string(), slot).unwrap_err();
+    assert_eq!(status(drop("nope", 1)), StatusCode::BAD_REQUEST);
+    assert_eq!(
+      status(drop("littledrink", 1)),
+      StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(status(drop("bigdrink", 3)), StatusCode::BAD_REQUEST);
+    // Out of stock, even though nobody marked it empty
+    backend.state.lock().unwrap().drinks.machines[0].slots[0].count = Some(0);
+    assert_eq!(status(drop("bigdrink", 1)), StatusCode::BAD_REQUEST);
+    assert_eq!(backend.credits(), 200);
+  }
+
+  #[test]
+  fn plan_drop_uses_current_state() {
+    let backend = MemoryBackend::sample();
+    let plan = backend.plan_drop("bigdrink", 1).unwrap();
+    assert_eq!(plan.new_balance(), 150);
+    assert!(matches!(
+      backend.plan_drop("bigdrink", 9),
+      Err(APIError::UnknownSlot(_, 9))
+    ));
+  }
+}
@@ -0,0 +1,110 @@
+use crate::api::{
+  APIError, DeviceCode, DrinkClient, DrinkList, DropPlan, Logout, PasswordFunction, User,
+};
+
+mod memory;
+
+pub use memory::MemoryBackend;
+
+/// Somewhere drinks come from. [`DrinkClient`] talks to the real drink API,
+/// [`MemoryBackend`] keeps everything in memory so tests don't need a network
+/// or a Kerberos ticket.
+pub trait DrinkBackend: Send + Sync {
+  /// Status of one machine, or all of them if `machine` is `None`
+  fn get_status_for_machine(&self, machine: Option<&str>) -> Result<DrinkList, APIError>;
+
+  /// The user's drink credit balance
+  fn get_credits(&self) -> Result<i64, APIError>;
+
+  /// Drops whatever is in `slot` of `machine`, returning the new balance.
+  /// This doesn't check anything first, see [`DrinkBackend::plan_drop`].
+  fn drop(&self, machine: String, slot: u8) -> Result<i64, APIError>;
+
+  /// Who we're acting as
+  fn get_user_info(&self) -> Result<User, APIError>;
+
+  /// The `Authorization` header value requests are sent with
+  fn get_token(&self) -> Result<String, APIError>;
+
+  /// Gets a fresh token, and a fresh Kerberos ticket too with
+  /// `force_password`. Backends that never log in just say who we are.
+  fn login(&self, _force_password: bool) -> Result<User, APIError> {
+    self.get_user_info()
+  }
+
+  /// Logs in with a code the user approves in a browser, see
+  /// [`DrinkClient::login_device`]
+  fn login_device(&self, _show: &mut dyn FnMut(&DeviceCode)) -> Result<User, APIError> {
+    self.get_user_info()
+  }
+  /// Forgets our credentials, see [`DrinkClient::logout`]
+  fn logout(&self, _destroy_ticket: bool) -> Result<Logout, APIError> {
+    Ok(Logout::default())
+  }
+
+  /// When our token expires, in seconds since the unix epoch
+  fn token_expires_at(&self) -> Option<u64> {
+    None
+  }
+
+  /// When our Kerberos ticket expires, in seconds since the unix epoch
+  fn ticket_expires_at(&self) -> Option<u64> {
+    None
+  }
+
+  /// Replaces the password prompt. Backends that never log in can ignore it.
+  fn set_password_prompt(&mut self, _prompt: Box<PasswordFunction>) {}
+
+  /// Fetches the machine's status and our balance, so the drop can be checked
+  /// before we spend anything
+  fn plan_drop(&self, machine: &str, slot: u8) -> Result<DropPlan, APIError> {
+    let drinks = self.get_status_for_machine(Some(machine))?;
+    DropPlan::new(&drinks, machine, slot, self.get_credits()?)
+  }
+}
+
+impl DrinkBackend for DrinkClient {
+  fn get_status_for_machine(&self, machine: Option<&str>) -> Result<DrinkList, APIError> {
+    DrinkClient::get_status_for_machine(self, machine)
+  }
+
+  fn get_credits(&self) -> Result<i64, APIError> {
+    DrinkClient::get_credits(self)
+  }
+
+  fn drop(&self, machine: String, slot: u8) -> Result<i64, APIError> {
+    DrinkClient::drop(self, machine, slot)
+  }
+
+  fn get_user_info(&self) -> Result<User, APIError> {
+    DrinkClient::get_user_info(self)
+  }
+
+  fn get_token(&self) -> Result<String, APIError> {
+    DrinkClient::get_token(self)
+  }
+
+  fn login(&self, force_password: bool) -> Result<User, APIError> {
+    DrinkClient::login(self, force_password)
+  }
+
+  fn login_device(&self, show: &mut dyn FnMut(&DeviceCode)) -> Result<User, APIError> {
+    DrinkClient::login_device(self, show)
+  }
+
+  fn logout(&self, destroy_ticket: bool) -> Result<Logout, APIError> {
+    DrinkClient::logout(self, destroy_ticket)
+  }
+
+  fn token_expires_at(&self) -> Option<u64> {
+    DrinkClient::token_expires_at(self)
+  }
+
+  fn ticket_expires_at(&self) -> Option<u64> {
+    DrinkClient::ticket_expires_at(self)
+  }
+
+  fn set_password_prompt(&mut self, prompt: Box<PasswordFunction>) {
+    DrinkClient::set_password_prompt(self, prompt)
+  }
+}
@@ -0,0 +1,576 @@
+//! A stand-in for the drink API and CSH SSO, so clink can be run (and tested)
+//! without either. Point clink at it with
+//! `clink --api http://127.0.0.1:PORT --sso-url http://127.0.0.1:PORT`.
+
+use clap::Parser;
+use clink::api::{DrinkList, Item, Machine, Slot};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+use url::form_urlencoded;
+
+/// Served when no fixture is given
+const DEFAULT_FIXTURE: &str = include_str!("../../fixtures/mock.toml");
+
+#[derive(Parser)]
+#[clap(version, about = "A fake drink API and SSO server to point clink at")]
+struct Args {
+  /// Machines, slots and users to serve, as JSON or TOML (see fixtures/mock.toml)
+  fixture: Option<PathBuf>,
+  /// Address to listen on
+  #[clap(long, default_value = "127.0.0.1")]
+  host: String,
+  /// Port to listen on, 0 picks a free one
+  #[clap(long, short, default_value_t = 0)]
+  port: u16,
+  /// Who SSO hands tokens out to [default: the first user in the fixture]
+  #[clap(long)]
+  user: Option<String>,
+  /// Take a machine offline, can be given more than once
+  #[clap(long, value_name = "MACHINE")]
+  offline: Vec<String>,
+  /// Answer requests for paths starting with PATH with a 500, can be given
+  /// more than once
+  #[clap(long, value_name = "PATH")]
+  fail: Vec<String>,
+  /// Answer requests for paths starting with PATH with truncated JSON, can be
+  /// given more than once
+  #[clap(long, value_name = "PATH")]
+  malformed: Vec<String>,
+  /// Answer requests for paths starting with PATH with a 503, can be given
+  /// more than once
+  #[clap(long, value_name = "PATH")]
+  unavailable: Vec<String>,
+  /// Answer the next request for a path starting with PATH with a 503, like
+  /// a server that's restarting. Give it more than once to fail more.
+  #[clap(long, value_name = "PATH")]
+  flaky: Vec<String>,
+  /// Wait this long before answering each request
+  #[clap(long, value_name = "MS", default_value_t = 0)]
+  delay: u64,
+  /// How long tokens are good for, in seconds
+  #[clap(long, value_name = "SECS", default_value_t = 300)]
+  token_lifetime: u64,
+  /// Stop accepting each token after its first use, like SSO revoking it
+  #[clap(long)]
+  expire_tokens: bool,
+  /// Accept KEY as a bearer token that never expires
+  #[clap(long, value_name = "KEY")]
+  api_key: Option<String>,
+  /// Let the token endpoint trade TOKEN for an access token. Refresh tokens
+  /// only work once, each response has the next one.
+  #[clap(long, value_name = "TOKEN")]
+  refresh_token: Option<String>,
+  /// Don't hand out tokens over SPNEGO, like SSO off the CSH network
+  #[clap(long)]
+  no_kerberos: bool,
+  /// Say a device login is still pending this many times before approving it
+  #[clap(long, value_name = "N", default_value_t = 1)]
+  device_polls: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+  #[serde(default)]
+  users: Vec<UserFixture>,
+  #[serde(default)]
+  machines: Vec<MachineFixture>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFixture {
+  username: String,
+  credits: i64,
+  /// Full name, defaults to `username`
+  name: Option<String>,
+  #[serde(default)]
+  groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MachineFixture {
+  name: String,
+  /// Defaults to `name`
+  display_name: Option<String>,
+  #[serde(default = "yes")]
+  online: bool,
+  #[serde(default)]
+  slots: Vec<SlotFixture>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlotFixture {
+  number: u8,
+  item: String,
+  price: u64,
+  /// Only for machines that can count, leave it out otherwise
+  count: Option<u64>,
+  #[serde(default = "yes")]
+  active: bool,
+  #[serde(default)]
+  empty: bool,
+}
+
+fn yes() -> bool {
+  true
+}
+
+impl Fixture {
+  fn load(path: Option<&Path>) -> Result<Fixture, String> {
+    let path = match path {
+      Some(path) => path,
+      None => return toml::from_str(DEFAULT_FIXTURE).map_err(|err| err.to_string()),
+    };
+    let contents = fs::read_to_string(path)
+      .map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+      Some("json") => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+      _ => toml::from_str(&contents).map_err(|err| err.to_string()),
+    }
+    .map_err(|err| format!("Couldn't parse {}: {}", path.display(), err))
+  }
+
+  /// The fixture in the shape `GET /drinks` returns it
+  fn drinks(&self) -> DrinkList {
+    let mut item_id = 0;
+    let machines = self
+      .machines
+      .iter()
+      .zip(1..)
+      .map(|(machine, id)| Machine {
+        display_name: machine
+          .display_name
+          .clone()
+          .unwrap_or_else(|| machine.name.clone()),
+        id,
+        is_online: machine.online,
+        name: machine.name.clone(),
+        slots: machine
+          .slots
+          .iter()
+          .map(|slot| {
+            item_id += 1;
+            Slot {
+              active: slot.active,
+              count: slot.count,
+              empty: slot.empty || slot.count == Some(0),
+              item: Item {
+                id: item_id,
+                name: slot.item.clone(),
+                price: slot.price,
+              },
+              machine: id,
+              number: slot.number,
+            }
+          })
+          .collect(),
+      })
+      .collect();
+    DrinkList {
+      machines,
+      message: "Successfully retrieved machine contents".to_string(),
+    }
+  }
+}
+
+struct IssuedToken {
+  username: String,
+  expires_at: Instant,
+  used: bool,
+}
+
+struct MockServer {
+  args: Args,
+  drinks: DrinkList,
+  credits: HashMap<String, i64>,
+  /// What userinfo says about each user
+  userinfo: HashMap<String, Value>,
+  /// Who SSO says you are
+  user: String,
+  tokens: HashMap<String, IssuedToken>,
+  /// Refresh tokens that haven't been used yet
+  refresh_tokens: HashSet<String>,
+  /// Device codes, and how many more times they'll be pending
+  device_codes: HashMap<String, u32>,
+  /// Paths that'll get a 503 next time, one request each
+  flaky: Vec<String>,
+}
+
+type MockResponse = Response<Cursor<Vec<u8>>>;
+
+fn json_response(status: u16, body: Value) -> MockResponse {
+  Response::from_string(body.to_string())
+    .with_status_code(status)
+    .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+  request
+    .headers()
+    .iter()
+    .find(|header| header.field.equiv(name))
+    .map(|header| header.value.as_str())
+}
+
+/// Errors look like the drink API's: `{"error": "...", "errorCode": 400}`
+fn error_response(status: u16, message: &str) -> MockResponse {
+  json_response(status, json!({ "error": message, "errorCode": status }))
+}
+
+impl MockServer {
+  fn new(args: Args, fixture: Fixture) -> Result<MockServer, String> {
+    let mut drinks = fixture.drinks();
+    for name in &args.offline {
+      drinks
+        .machines
+        .iter_mut()
+        .find(|machine| &machine.name == name)
+        .ok_or_else(|| format!("{} isn't in the fixture", name))?
+        .is_online = false;
+    }
+    let user = match &args.user {
+      Some(user) => user.clone(),
+      None => fixture
+        .users
+        .first()
+        .map(|user| user.username.clone())
+        .ok_or("The fixture doesn't have any users")?,
+    };
+    let userinfo: HashMap<String, Value> = fixture
+      .users
+      .iter()
+      .map(|user| {
+        (
+          user.username.clone(),
+          json!({
+            "sub": user.username,
+            "preferred_username": user.username,
+            "name": user.name.as_ref().unwrap_or(&user.username),
+            "groups": user.groups,
+          }),
+        )
+      })
+      .collect();
+    let credits: HashMap<String, i64> = fixture
+      .users
+      .into_iter()
+      .map(|user| (user.username, user.credits))
+      .collect();
+    if !credits.contains_key(&user) {
+      return Err(format!("{} isn't in the fixture", user));
+    }
+    let refresh_tokens = args.refresh_token.iter().cloned().collect();
+    let flaky = args.flaky.clone();
+    Ok(MockServer {
+      args,
+      drinks,
+      credits,
+      userinfo,
+      user,
+      tokens: HashMap::new(),
+      refresh_tokens,
+      device_codes: HashMap::new(),
+      flaky,
+    })
+  }
+
+  fn handle(&mut self, request: &mut Request) -> MockResponse {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+      .into_owned()
+      .collect();
+
+    if self.args.fail.iter().any(|prefix| path.starts_with(prefix)) {
+      return error_response(500, "Internal Server Error");
+    }
+    if self
+      .args
+      .malformed
+      .iter()
+      .any(|prefix| path.starts_with(prefix))
+    {
+      return Response::from_string("{\"machines\": [{\"name\": ")
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    }
+    if path.ends_with("/protocol/openid-connect/auth") {
+      return match self.args.no_kerberos {
+        // What Keycloak does without a ticket: a login page, no redirect
+        true => Response::from_string("").with_status_code(401),
+        false => self.issue_token(),
+      };
+    }
+    if path.ends_with("/protocol/openid-connect/auth/device") {
+      return self.device_code(request);
+    }
+    if path.ends_with("/protocol/openid-connect/token") {
+      let mut body = String::new();
+      request.as_reader().read_to_string(&mut body).ok();
+      return self.token_endpoint(&body);
+    }
+    if let Some(index) = self
+      .flaky
+      .iter()
+      .position(|prefix| path.starts_with(prefix))
+    {
+      self.flaky.remove(index);
+      return error_response(503, "Service Unavailable");
+    }
+    if self
+      .args
+      .unavailable
+      .iter()
+      .any(|prefix| path.starts_with(prefix))
+    {
+      return error_response(503, "Service Unavailable");
+    }
+    let username = match self.authenticate(request) {
+      Ok(username) => username,
+      Err(response) => return response,
+    };
+    match (request.method(), path) {
+      (Method::Get, path) if path.ends_with("/protocol/openid-connect/userinfo") => {
+        json_response(200, self.userinfo[&username].clone())
+      }
+      (Method::Get, "/drinks") => self.status(query.get("machine")),
+      (Method::Post, "/drinks/drop") => {
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).ok();
+        match serde_json::from_str::<Value>(&body) {
+          Ok(body) => self.drop(&username, &body),
+          Err(_) => error_response(400, "Request body must be JSON"),
+        }
+      }
+      (Method::Get, "/users/credits") => match query.get("uid") {
+        Some(uid) => self.user_credits(uid),
+        None => error_response(400, "Missing uid"),
+      },
+      _ => error_response(404, "Not Found"),
+    }
+  }
+
+  fn new_token(&mut self) -> String {
+    let token = uuid::Uuid::new_v4().to_string();
+    self.tokens.insert(
+      token.clone(),
+      IssuedToken {
+        username: self.user.clone(),
+        expires_at: Instant::now() + Duration::from_secs(self.args.token_lifetime),
+        used: false,
+      },
+    );
+    token
+  }
+
+  /// SSO's implicit flow: redirect to the client with a token in the fragment
+  fn issue_token(&mut self) -> MockResponse {
+    let token = self.new_token();
+    let location = format!(
+      "drink://callback#state=&session_state=mock&access_token={}&token_type=bearer&expires_in={}",
+      token, self.args.token_lifetime
+    );
+    Response::from_string("")
+      .with_status_code(302)
+      .with_header(Header::from_bytes("Location", location).unwrap())
+  }
+
+  /// SSO's token endpoint, for the refresh_token and device_code grants.
+  /// Refresh tokens are rotated on every use.
+  fn token_endpoint(&mut self, body: &str) -> MockResponse {
+    let form: HashMap<String, String> = form_urlencoded::parse(body.as_bytes())
+      .into_owned()
+      .collect();
+    let oauth_error = |error: &str| json_response(400, json!({ "error": error }));
+    match form.get("grant_type").map(String::as_str) {
+      Some("refresh_token") => {
+        let used = form
+          .get("refresh_token")
+          .is_some_and(|token| self.refresh_tokens.remove(token));
+        if !used {
+          return oauth_error("invalid_grant");
+        }
+      }
+      Some("urn:ietf:params:oauth:grant-type:device_code") => {
+        let code = form.get("device_code").map(String::as_str).unwrap_or("");
+        match self.device_codes.get_mut(code) {
+          None => return oauth_error("invalid_grant"),
+          Some(0) => {
+            self.device_codes.remove(code);
+          }
+          Some(polls) => {
+            *polls -= 1;
+            return oauth_error("authorization_pending");
+          }
+        }
+      }
+      _ => return oauth_error("unsupported_grant_type"),
+    }
+    let refresh_token = uuid::Uuid::new_v4().to_string();
+    self.refresh_tokens.insert(refresh_token.clone());
+    json_response(
+      200,
+      json!({
+        "access_token": self.new_token(),
+        "expires_in": self.args.token_lifetime,
+        "refresh_token": refresh_token,
+        "token_type": "Bearer",
+      }),
+    )
+  }
+
+  /// Starts a device login, which gets approved after `--device-polls`
+  /// checks. Nobody has to visit the URL.
+  fn device_code(&mut self, request: &Request) -> MockResponse {
+    let code = uuid::Uuid::new_v4().to_string();
+    self
+      .device_codes
+      .insert(code.clone(), self.args.device_polls);
+    let host = header(request, "Host").unwrap_or("127.0.0.1");
+    json_response(
+      200,
+      json!({
+        "device_code": code,
+        "user_code": "MOCK-CODE",
+        "verification_uri": format!("http://{}/device", host),
+        "verification_uri_complete": format!("http://{}/device?user_code=MOCK-CODE", host),
+        "expires_in": 600,
+        "interval": 0,
+      }),
+    )
+  }
+
+  /// Who the request's bearer token belongs to
+  fn authenticate(&mut self, request: &Request) -> Result<String, MockResponse> {
+    let unauthorized = || error_response(401, "Unauthorized");
+    let token = header(request, "Authorization")
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .ok_or_else(unauthorized)?;
+    if self.args.api_key.as_deref() == Some(token) {
+      return Ok(self.user.clone());
+    }
+    let issued = self.tokens.get_mut(token).ok_or_else(unauthorized)?;
+    if Instant::now() >= issued.expires_at || (self.args.expire_tokens && issued.used) {
+      return Err(unauthorized());
+    }
+    issued.used = true;
+    Ok(issued.username.clone())
+  }
+
+  fn status(&self, machine: Option<&String>) -> MockResponse {
+    let mut drinks = self.drinks.clone();
+    if let Some(name) = machine {
+      drinks.machines.retain(|machine| &machine.name == name);
+      if drinks.machines.is_empty() {
+        return error_response(400, "Invalid machine name");
+      }
+    }
+    json_response(200, serde_json::to_value(drinks).unwrap())
+  }
+
+  fn user_credits(&self, uid: &str) -> MockResponse {
+    match self.credits.get(uid) {
+      // LDAP hands the balance back as a string, so the real API does too
+      Some(credits) => json_response(
+        200,
+        json!({
+          "message": format!("Retrieved user with uid {}", uid),
+          "user": { "uid": uid, "cn": uid, "drinkBalance": credits.to_string() },
+        }),
+      ),
+      None => error_response(404, "User not found"),
+    }
+  }
+
+  fn drop(&mut self, username: &str, body: &Value) -> MockResponse {
+    let (Some(machine), Some(slot)) = (body["machine"].as_str(), body["slot"].as_u64()) else {
+      return error_response(400, "Missing machine or slot");
+    };
+    let Some(machine) = self
+      .drinks
+      .machines
+      .iter_mut()
+      .find(|status| status.name == machine)
+    else {
+      return error_response(400, "Invalid machine name");
+    };
+    if !machine.is_online {
+      return error_response(503, "Machine is offline");
+    }
+    let Some(slot) = machine
+      .slots
+      .iter_mut()
+      .find(|status| status.number as u64 == slot)
+    else {
+      return error_response(400, "Invalid slot");
+    };
+    if !slot.active || slot.empty || slot.count == Some(0) {
+      return error_response(400, "Slot is empty or disabled");
+    }
+    let balance = self.credits.get_mut(username).unwrap();
+    let price = slot.item.price as i64;
+    if *balance < price {
+      return error_response(402, "Not enough credits");
+    }
+    if let Some(count) = slot.count.as_mut() {
+      *count -= 1;
+      slot.empty = *count == 0;
+    }
+    *balance -= price;
+    json_response(
+      200,
+      json!({
+        "message": format!("Dropped drink from slot {} in {}", slot.number, machine.display_name),
+        "drinkBalance": *balance,
+      }),
+    )
+  }
+}
+
+fn main() -> ExitCode {
+  let args = Args::parse();
+  let server = match Fixture::load(args.fixture.as_deref())
+    .and_then(|fixture| MockServer::new(args, fixture))
+  {
+    Ok(server) => server,
+    Err(err) => {
+      eprintln!("Error: {}", err);
+      return ExitCode::FAILURE;
+    }
+  };
+  let http = match Server::http((server.args.host.as_str(), server.args.port)) {
+    Ok(http) => http,
+    Err(err) => {
+      eprintln!("Error: Couldn't listen: {}", err);
+      return ExitCode::FAILURE;
+    }
+  };
+  // Tests wait for this line to know where to point clink
+  println!("Listening on http://{}", http.server_addr());
+  io::stdout().flush().ok();
+
+  let delay = Duration::from_millis(server.args.delay);
+  let mut server = server;
+  for mut request in http.incoming_requests() {
+    thread::sleep(delay);
+    let response = server.handle(&mut request);
+    eprintln!(
+      "{} {} {}",
+      request.method(),
+      request.url(),
+      response.status_code().0
+    );
+    request.respond(response).ok();
+  }
+  ExitCode::SUCCESS
+}
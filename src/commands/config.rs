@@ -0,0 +1,68 @@
+use crate::config::{self, Config, ConfigError, SYSTEM_CONFIG_PATH};
+
+fn format_value(value: &toml::Value) -> String {
+  match value {
+    toml::Value::String(value) => value.clone(),
+    value => value.to_string(),
+  }
+}
+
+/// Options that are as good as a password, at the top level or in a profile
+const SECRETS: [&str; 2] = ["auth.token", "auth.refresh_token"];
+
+fn is_secret(key: &str) -> bool {
+  SECRETS
+    .iter()
+    .any(|secret| key == *secret || key.ends_with(&format!(".{}", secret)))
+}
+
+fn print_option(key: &str, value: &toml::Value, show_secrets: bool) {
+  if is_secret(key) && !show_secrets {
+    println!("{} = <redacted>", key);
+  } else {
+    println!("{} = {}", key, value);
+  }
+}
+
+/// Asking for a secret by name prints it, it only gets hidden in a table
+pub fn get(key: &str, show_secrets: bool) -> Result<(), ConfigError> {
+  let table = Config::load_table()?;
+  match config::get(&table, key) {
+    Some(toml::Value::Table(inner)) => {
+      for (inner_key, value) in config::flatten(inner) {
+        print_option(&format!("{}.{}", key, inner_key), value, show_secrets);
+      }
+      Ok(())
+    }
+    Some(value) => {
+      println!("{}", format_value(value));
+      Ok(())
+    }
+    None => Err(ConfigError::NotSet(key.to_string())),
+  }
+}
+
+pub fn set(key: &str, value: &str) -> Result<(), ConfigError> {
+  let path = Config::set(key, value)?;
+  println!("Set {} in {}", key, path.display());
+  Ok(())
+}
+
+pub fn list(show_secrets: bool) -> Result<(), ConfigError> {
+  for (key, value) in config::flatten(&Config::load_table()?) {
+    print_option(&key, value, show_secrets);
+  }
+  Ok(())
+}
+
+pub fn path(system: bool) -> Result<(), ConfigError> {
+  if system {
+    println!("{}", SYSTEM_CONFIG_PATH);
+  } else {
+    println!(
+      "{}",
+      Config::path().ok_or(ConfigError::NoConfigDir)?.display()
+    );
+  }
+  Ok(())
+}
@@ -1,8 +1,37 @@
-use crate::api::{APIError, API};
+use crate::output::{self, Output, OutputFormat};
+use clink::api::APIError;
+use clink::backend::DrinkBackend;
+use serde::Serialize;
+use std::io::Write;
 
-pub fn credits(api: &mut API) -> Result<(), APIError> {
+#[derive(Serialize, Clone)]
+pub struct Credits {
+  credits: i64,
+}
+
+impl Output for Credits {
+  type Record = Credits;
+
+  fn command(&self) -> &'static str {
+    "credits"
+  }
+
+  fn text(&self) -> String {
+    format!("{} credits\n", self.credits)
+  }
+
+  fn records(&self) -> Vec<Credits> {
+    vec![self.clone()]
+  }
+}
+
+pub fn credits(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  format: OutputFormat,
+) -> Result<(), APIError> {
   let credits = api.get_credits()?;
-  println!("{} credits", credits);
+  output::print(out, format, &Credits { credits });
 
   Ok(())
 }
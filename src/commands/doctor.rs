@@ -0,0 +1,434 @@
+use crate::commands::whoami::describe_expiry;
+use crate::output::{self, Output, OutputFormat};
+use clink::api::{APIError, DrinkClient, StaticToken};
+use clink::kerberos;
+use clink::token_cache::now;
+use isahc::error::ErrorKind;
+use isahc::{ReadResponseExt, Request};
+use serde::Serialize;
+use std::env;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// How long to wait when checking that a server is reachable at all
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Kerberos' default, past this tickets stop working
+const MAX_CLOCK_SKEW: u64 = 300;
+/// Tickets that expire sooner than this get a warning
+const EXPIRY_WARNING: u64 = 10 * 60;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  Pass,
+  Warn,
+  Fail,
+  /// Couldn't run, because something it needs failed first
+  Skip,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Check {
+  name: String,
+  status: Status,
+  detail: String,
+  /// What to do about it
+  tip: Option<String>,
+}
+
+impl Check {
+  fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Check {
+    Check {
+      name: name.into(),
+      status,
+      detail: detail.into(),
+      tip: None,
+    }
+  }
+
+  fn tip(mut self, tip: impl Into<String>) -> Check {
+    self.tip = Some(tip.into());
+    self
+  }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Doctor {
+  checks: Vec<Check>,
+}
+
+impl Output for Doctor {
+  type Record = Check;
+
+  fn command(&self) -> &'static str {
+    "doctor"
+  }
+
+  fn text(&self) -> String {
+    let mut text = String::new();
+    for check in &self.checks {
+      let status = match check.status {
+        Status::Pass => " ok ",
+        Status::Warn => "warn",
+        Status::Fail => "FAIL",
+        Status::Skip => "skip",
+      };
+      text.push_str(&format!("[{}] {}: {}\n", status, check.name, check.detail));
+      if let Some(tip) = &check.tip {
+        text.push_str(&format!("       {}\n", tip));
+      }
+    }
+    text
+  }
+
+  fn records(&self) -> Vec<Check> {
+    self.checks.clone()
+  }
+}
+
+const GET_A_TICKET: &str = "Get one with `clink login --password` (or `kinit`)";
+
+/// Runs every check and prints the results. Returns false if anything failed.
+pub fn doctor(api: &DrinkClient, out: &mut impl Write, format: OutputFormat) -> bool {
+  let mut checks = Vec::new();
+
+  // Kerberos
+  if api.auth().uses_kerberos() {
+    checks.push(check_ccache());
+    let ticket = kerberos::ticket();
+    checks.push(check_ticket(ticket.as_ref()));
+    checks.push(match &ticket {
+      Some(ticket) if ticket.realm() == api.sso().kerberos_realm => {
+        Check::new("Kerberos realm", Status::Pass, ticket.realm())
+      }
+      Some(ticket) => Check::new(
+        "Kerberos realm",
+        Status::Fail,
+        format!(
+          "Your ticket is for {}, but clink logs in to {}",
+          ticket.realm(),
+          api.sso().kerberos_realm
+        ),
+      )
+      .tip("Get a ticket for the right realm, or set `sso.kerberos_realm` in your config"),
+      None => Check::new("Kerberos realm", Status::Skip, "No ticket to check"),
+    });
+    checks.push(check_kinit());
+  } else {
+    checks.push(Check::new(
+      "Kerberos",
+      Status::Skip,
+      format!(
+        "Logging in with {}, which doesn't need it",
+        api.auth().name()
+      ),
+    ));
+  }
+
+  // Servers
+  let mut server_time = None;
+  for (label, url) in [
+    ("SSO", &api.sso().url),
+    ("Drink API", &api.base_url().to_string()),
+  ] {
+    // A proxy might be the only way out, so connecting ourselves proves
+    // nothing. The HTTPS check goes through it like everything else.
+    let reachable = match proxy_for(api, url) {
+      Some(proxy) => Check::new(
+        format!("{} reachable", label),
+        Status::Skip,
+        format!("Going through a proxy ({})", proxy),
+      ),
+      None => check_reachable(label, url),
+    };
+    let ok = reachable.status != Status::Fail;
+    checks.push(reachable);
+    if !ok {
+      checks.push(Check::new(
+        format!("{} HTTPS", label),
+        Status::Skip,
+        "Server isn't reachable",
+      ));
+      continue;
+    }
+    let (check, date) = check_https(api, label, url);
+    checks.push(check);
+    server_time = server_time.or(date);
+  }
+  checks.push(check_clock(server_time));
+
+  // Tokens and the API itself
+  let token = if api.auth().can_refresh() {
+    let (check, client) = check_token(api);
+    checks.push(check);
+    client
+  } else {
+    checks.push(Check::new(
+      "SSO token",
+      Status::Skip,
+      "Using a token you gave us",
+    ));
+    Some(api.clone())
+  };
+  checks.push(match token {
+    Some(client) => check_drinks(&client),
+    None => Check::new("Drink list", Status::Skip, "No token to ask with"),
+  });
+
+  let passed = checks.iter().all(|check| check.status != Status::Fail);
+  output::print(out, format, &Doctor { checks });
+  passed
+}
+
+fn check_ccache() -> Check {
+  let name = match kerberos::ccache_name() {
+    Some(name) => name,
+    None => {
+      return Check::new(
+        "Credential cache",
+        Status::Warn,
+        "Couldn't tell where it is (KRB5CCNAME isn't set)",
+      )
+    }
+  };
+  // Only files can be checked from out here, the rest live in the kernel or
+  // a daemon
+  let path = match name.split_once(':') {
+    Some(("FILE", path)) => Some(path),
+    Some(_) => None,
+    None => Some(name.as_str()),
+  };
+  match path {
+    Some(path) if !Path::new(path).exists() => Check::new(
+      "Credential cache",
+      Status::Fail,
+      format!("{} doesn't exist", name),
+    )
+    .tip(GET_A_TICKET),
+    _ => Check::new("Credential cache", Status::Pass, name),
+  }
+}
+
+fn check_ticket(ticket: Option<&kerberos::Ticket>) -> Check {
+  let name = "Kerberos ticket";
+  let ticket = match ticket {
+    Some(ticket) => ticket,
+    None if cfg!(feature = "krb5") => {
+      return Check::new(name, Status::Fail, "No ticket found").tip(GET_A_TICKET)
+    }
+    None => {
+      return Check::new(
+        name,
+        Status::Skip,
+        "clink was built without the krb5 feature, check with `klist`",
+      )
+    }
+  };
+  let expires_at = match ticket.expires_at {
+    Some(expires_at) => expires_at,
+    None => {
+      return Check::new(
+        name,
+        Status::Fail,
+        format!("No ticket-granting ticket for {}", ticket.principal),
+      )
+      .tip(GET_A_TICKET)
+    }
+  };
+  let detail = format!(
+    "{}, {}",
+    ticket.principal,
+    describe_expiry(Some(expires_at), now())
+  );
+  if expires_at <= now() {
+    Check::new(name, Status::Fail, detail).tip(GET_A_TICKET)
+  } else if expires_at - now() < EXPIRY_WARNING {
+    Check::new(name, Status::Warn, detail).tip(GET_A_TICKET)
+  } else {
+    Check::new(name, Status::Pass, detail)
+  }
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+  env::split_paths(&env::var_os("PATH")?)
+    .map(|dir| dir.join(program))
+    .find(|path| path.is_file())
+}
+
+fn check_kinit() -> Check {
+  match find_in_path("kinit") {
+    Some(path) => Check::new("kinit", Status::Pass, path.display().to_string()),
+    None if cfg!(feature = "krb5") => Check::new(
+      "kinit",
+      Status::Pass,
+      "Not installed, but clink can log in without it",
+    ),
+    None => Check::new("kinit", Status::Fail, "Not on your PATH")
+      .tip("Install your Kerberos client package (krb5-user, krb5-workstation, ...)"),
+  }
+}
+
+/// The proxy requests to `url` go through, if there is one: ours, or one
+/// from the variables curl looks at
+fn proxy_for(api: &DrinkClient, url: &str) -> Option<String> {
+  if let Some(proxy) = api.proxy() {
+    return Some(proxy.to_string());
+  }
+  let vars: &[&str] = match url.starts_with("https:") {
+    true => &["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"],
+    // curl ignores HTTP_PROXY, since CGI sets it from a header
+    false => &["http_proxy", "all_proxy", "ALL_PROXY"],
+  };
+  vars
+    .iter()
+    .find_map(|var| env::var(var).ok().filter(|value| !value.is_empty()))
+}
+
+/// Looks the host up and opens a TCP connection to it
+fn check_reachable(label: &str, url: &str) -> Check {
+  let name = format!("{} reachable", label);
+  let url = match Url::parse(url) {
+    Ok(url) => url,
+    Err(err) => return Check::new(name, Status::Fail, format!("Bad URL {}: {}", url, err)),
+  };
+  let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+    (Some(host), Some(port)) => (host, port),
+    _ => return Check::new(name, Status::Fail, format!("Bad URL {}", url)),
+  };
+  let addr = match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+    Ok(Some(addr)) => addr,
+    Ok(None) | Err(_) => {
+      return Check::new(name, Status::Fail, format!("Couldn't look up {}", host))
+        .tip("Check your network connection and DNS")
+    }
+  };
+  match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+    Ok(_) => Check::new(name, Status::Pass, format!("{}:{} ({})", host, port, addr)),
+    Err(err) => Check::new(
+      name,
+      Status::Fail,
+      format!("Couldn't connect to {}:{} ({}): {}", host, port, addr, err),
+    )
+    .tip("Check your network connection, or whether you need a VPN"),
+  }
+}
+
+/// Makes a plain request, which is enough to check TLS. Also returns the
+/// server's clock, for checking ours.
+fn check_https(api: &DrinkClient, label: &str, url: &str) -> (Check, Option<SystemTime>) {
+  let name = format!("{} HTTPS", label);
+  let request = match Request::get(url).body(()) {
+    Ok(request) => request,
+    Err(err) => return (Check::new(name, Status::Fail, err.to_string()), None),
+  };
+  match api.http_client().send(request) {
+    Ok(mut response) => {
+      // Don't leave the body in the connection
+      response.consume().ok();
+      let date = response
+        .headers()
+        .get("Date")
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+      let detail = match url.starts_with("https:") {
+        true => format!("Certificate is valid ({})", response.status()),
+        false => format!("Not using TLS ({})", response.status()),
+      };
+      (Check::new(name, Status::Pass, detail), date)
+    }
+    Err(err) => {
+      let check = match err.kind() {
+        ErrorKind::BadServerCertificate
+        | ErrorKind::TlsEngine
+        | ErrorKind::BadClientCertificate => {
+          Check::new(name, Status::Fail, format!("TLS failed: {}", err))
+            .tip("Check your system's CA certificates, and that your clock is right")
+        }
+        _ => Check::new(name, Status::Fail, err.to_string()),
+      };
+      (check, None)
+    }
+  }
+}
+
+fn check_clock(server_time: Option<SystemTime>) -> Check {
+  let name = "Clock skew";
+  let server_time = match server_time {
+    Some(server_time) => server_time,
+    None => return Check::new(name, Status::Skip, "No server told us the time"),
+  };
+  let seconds = |time: SystemTime| {
+    time
+      .duration_since(UNIX_EPOCH)
+      .map(|duration| duration.as_secs())
+      .unwrap_or(0)
+  };
+  let skew = seconds(SystemTime::now()).abs_diff(seconds(server_time));
+  let detail = format!("{}s off from the server", skew);
+  if skew > MAX_CLOCK_SKEW {
+    Check::new(name, Status::Fail, detail)
+      .tip("Kerberos only allows 5 minutes, sync your clock (`timedatectl set-ntp true`)")
+  } else if skew > 60 {
+    Check::new(name, Status::Warn, detail).tip("Sync your clock before it gets worse")
+  } else {
+    Check::new(name, Status::Pass, detail)
+  }
+}
+
+/// Gets a token without logging in if that doesn't work. With Kerberos we
+/// really ask SSO, since a cached token says nothing about the ticket. Hands
+/// back a client that uses the token, so the next check doesn't have to log
+/// in either.
+fn check_token(api: &DrinkClient) -> (Check, Option<DrinkClient>) {
+  let name = "SSO token";
+  let token = if api.auth().uses_kerberos() {
+    api.request_token()
+  } else {
+    api.try_get_token()
+  };
+  let token = match token {
+    Ok(Some(token)) => token,
+    Ok(None) => {
+      return (
+        Check::new(
+          name,
+          Status::Fail,
+          "SSO didn't redirect us with a token, so SPNEGO didn't work",
+        )
+        .tip(GET_A_TICKET),
+        None,
+      )
+    }
+    Err(err) => return (Check::new(name, Status::Fail, err.to_string()), None),
+  };
+  let value = token.value.trim_start_matches("Bearer ").to_string();
+  let client = Some(api.with_auth(StaticToken::new(value)));
+  (
+    Check::new(
+      name,
+      Status::Pass,
+      format!("Got one, {}", describe_expiry(token.expires_at, now())),
+    ),
+    client,
+  )
+}
+
+fn check_drinks(client: &DrinkClient) -> Check {
+  let name = "Drink list";
+  match client.get_status_for_machine(None) {
+    Ok(drinks) => Check::new(
+      name,
+      Status::Pass,
+      format!("{} machines", drinks.machines.len()),
+    ),
+    Err(err @ APIError::BadFormat(_)) => Check::new(name, Status::Fail, err.to_string())
+      .tip("Run `clink list --verbose` and report what it says"),
+    Err(APIError::Unauthorized(_)) => {
+      Check::new(name, Status::Fail, "The API didn't accept our token")
+    }
+    Err(err) => Check::new(name, Status::Fail, err.to_string()),
+  }
+}
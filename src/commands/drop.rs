@@ -1,7 +1,429 @@
-use crate::api::{APIError, API};
+use crate::output::{self, Output, OutputFormat};
+use clink::api::{APIError, DrinkList, DropPlan, Item, Machine, Slot};
+use clink::backend::DrinkBackend;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde::Serialize;
+use std::io::{self, BufRead, IsTerminal, Write};
 
-pub fn drop(api: &mut API, machine: String, slot: u8) -> Result<(), APIError> {
-  let credits = api.drop(machine, slot)?;
-  println!("Item dropped! Your new balance is {}", credits);
+/// How many matches we're willing to show in the chooser
+const MAX_CHOICES: usize = 10;
+
+/// What the user asked us to drop
+pub enum DropTarget {
+  /// `clink drop bigdrink 3`
+  Slot { machine: String, slot: u8 },
+  /// `clink drop coke`, optionally only looking in one machine
+  Item {
+    query: String,
+    machine: Option<String>,
+  },
+}
+
+impl DropTarget {
+  /// Figures out what `clink drop <args...> [--machine <machine>]` meant.
+  /// clap makes sure there are one or two args.
+  pub fn from_args(mut args: Vec<String>, machine: Option<String>) -> DropTarget {
+    let query = args.pop().unwrap_or_default();
+    let machine = args.pop().or(machine);
+    match (machine, query.parse::<u8>()) {
+      (Some(machine), Ok(slot)) => DropTarget::Slot { machine, slot },
+      (machine, _) => DropTarget::Item { query, machine },
+    }
+  }
+}
+
+/// Flags that change how careful `drop` is
+pub struct DropOptions {
+  /// Skip the checks against machine state and balance
+  pub force: bool,
+  /// Ask before spending credits
+  pub confirm: bool,
+  /// Do everything except actually dropping
+  pub dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct DropOutput {
+  machine: String,
+  slot: u8,
+  /// What the machine said was in the slot before we dropped from it
+  item: Item,
+  new_balance: i64,
+  /// If true, nothing was dropped and `new_balance` is what it would have been
+  dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct DropRecord {
+  machine: String,
+  slot: u8,
+  item: String,
+  price: u64,
+  new_balance: i64,
+  dry_run: bool,
+}
+
+impl Output for DropOutput {
+  type Record = DropRecord;
+
+  fn command(&self) -> &'static str {
+    "drop"
+  }
+
+  fn text(&self) -> String {
+    match self.dry_run {
+      true => format!(
+        "Would drop {} from {}, slot {}. Your new balance would be {}\n",
+        self.item.name, self.machine, self.slot, self.new_balance
+      ),
+      false => format!("Item dropped! Your new balance is {}\n", self.new_balance),
+    }
+  }
+
+  fn records(&self) -> Vec<DropRecord> {
+    vec![DropRecord {
+      machine: self.machine.clone(),
+      slot: self.slot,
+      item: self.item.name.clone(),
+      price: self.item.price,
+      new_balance: self.new_balance,
+      dry_run: self.dry_run,
+    }]
+  }
+}
+
+/// A slot whose item matched what the user typed
+struct Candidate<'a> {
+  machine: &'a Machine,
+  slot: &'a Slot,
+  score: i64,
+  exact: bool,
+  available: bool,
+  affordable: bool,
+  preferred: bool,
+}
+
+impl<'a> Candidate<'a> {
+  fn droppable(&self) -> bool {
+    self.available && self.affordable
+  }
+
+  fn describe(&self) -> String {
+    let mut description = format!(
+      "{} ({} Credits) from {}, slot {}",
+      self.slot.item.name, self.slot.item.price, self.machine.display_name, self.slot.number
+    );
+    if !self.machine.is_online {
+      description.push_str(" [OFFLINE]");
+    } else if !self.available {
+      description.push_str(" [EMPTY]");
+    } else if !self.affordable {
+      description.push_str(" [TOO EXPENSIVE]");
+    }
+    description
+  }
+}
+
+/// Every slot in `drinks` whose item fuzzy-matches `query`, best first. Slots
+/// we can actually drop from always come before ones we can't.
+fn find_candidates<'a>(
+  drinks: &'a DrinkList,
+  query: &str,
+  credits: i64,
+  preferred_machine: Option<&str>,
+) -> Vec<Candidate<'a>> {
+  let matcher = SkimMatcherV2::default().ignore_case();
+  let mut candidates: Vec<Candidate> = drinks
+    .machines
+    .iter()
+    .flat_map(|machine| machine.slots.iter().map(move |slot| (machine, slot)))
+    .filter_map(|(machine, slot)| {
+      let score = matcher.fuzzy_match(&slot.item.name, query)?;
+      Some(Candidate {
+        machine,
+        slot,
+        score,
+        exact: slot.item.name.eq_ignore_ascii_case(query),
+        available: machine.is_online
+          && slot.active
+          && !slot.empty
+          && slot.count.map(|count| count > 0).unwrap_or(true),
+        affordable: credits >= slot.item.price as i64,
+        preferred: preferred_machine == Some(machine.name.as_str()),
+      })
+    })
+    .collect();
+  candidates.sort_by_key(|candidate| {
+    (
+      !candidate.droppable(),
+      !candidate.available,
+      !candidate.exact,
+      -candidate.score,
+      !candidate.preferred,
+    )
+  });
+  candidates
+}
+
+/// Picks a candidate on our own if there's an obvious one, otherwise asks
+fn choose<'a>(query: &str, mut candidates: Vec<Candidate<'a>>) -> Result<Candidate<'a>, APIError> {
+  let droppable = candidates.iter().filter(|c| c.droppable()).count();
+  let exact = candidates
+    .iter()
+    .filter(|c| c.droppable() && c.exact)
+    .count();
+  // Candidates are sorted, so the first one is the one we'd pick
+  if candidates.len() == 1 || droppable == 1 || exact == 1 {
+    return Ok(candidates.swap_remove(0));
+  }
+  if candidates.is_empty() {
+    return Err(APIError::ItemNotFound(query.to_string()));
+  }
+  // Nothing we could pick would work, so let the plan say why the best one
+  // doesn't
+  if droppable == 0 {
+    return Ok(candidates.swap_remove(0));
+  }
+  if !io::stdin().is_terminal() {
+    return Err(APIError::AmbiguousItem(query.to_string()));
+  }
+
+  candidates.truncate(MAX_CHOICES);
+  eprintln!("Found a few drinks matching {:?}:", query);
+  for (index, candidate) in candidates.iter().enumerate() {
+    eprintln!("{}. {}", index + 1, candidate.describe());
+  }
+  let mut line = String::new();
+  loop {
+    eprint!("Which one? [1-{}, blank to cancel]: ", candidates.len());
+    io::stderr().flush().ok();
+    line.clear();
+    if io::stdin().lock().read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+      return Err(APIError::Cancelled);
+    }
+    match line.trim().parse::<usize>() {
+      Ok(choice) if (1..=candidates.len()).contains(&choice) => {
+        return Ok(candidates.swap_remove(choice - 1));
+      }
+      _ => eprintln!("That's not one of the options"),
+    }
+  }
+}
+
+/// Shows the user what they're about to spend and asks if that's okay
+fn confirm(plan: &DropPlan) -> Result<(), APIError> {
+  if !io::stdin().is_terminal() {
+    return Err(APIError::NeedsConfirmation);
+  }
+  eprintln!(
+    "Drop {} from {}, slot {}? It costs {} credits, so your balance will go from {} to {}.",
+    plan.slot.item.name,
+    plan.machine.display_name,
+    plan.slot.number,
+    plan.price(),
+    plan.balance,
+    plan.new_balance()
+  );
+  eprint!("Continue? [y/N]: ");
+  io::stderr().flush().ok();
+  let mut line = String::new();
+  io::stdin().lock().read_line(&mut line).ok();
+  match line.trim().to_lowercase().as_str() {
+    "y" | "yes" => Ok(()),
+    _ => Err(APIError::Cancelled),
+  }
+}
+
+/// After a drop we lost track of, offers to look at the balance to see
+/// whether it went through
+fn offer_balance_check(api: &impl DrinkBackend, plan: &DropPlan) {
+  if !io::stdin().is_terminal() {
+    return;
+  }
+  eprint!(
+    "Lost track of the drop, so it may or may not have happened. Check your balance? [Y/n]: "
+  );
+  io::stderr().flush().ok();
+  let mut line = String::new();
+  io::stdin().lock().read_line(&mut line).ok();
+  if !matches!(line.trim().to_lowercase().as_str(), "" | "y" | "yes") {
+    return;
+  }
+  match api.get_credits() {
+    Ok(balance) => eprintln!("{}", plan.describe_outcome(balance)),
+    Err(err) => eprintln!("Couldn't check your balance either: {}", err),
+  }
+}
+
+pub fn drop(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  target: DropTarget,
+  preferred_machine: Option<String>,
+  options: DropOptions,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  // Tell the user what we picked when they didn't give an exact slot
+  let mut picked = None;
+  let plan = match target {
+    DropTarget::Slot { machine, slot } => api.plan_drop(&machine, slot)?,
+    DropTarget::Item { query, machine } => {
+      let drinks = api.get_status_for_machine(machine.as_deref())?;
+      let credits = api.get_credits()?;
+      let preferred_machine = machine.or(preferred_machine);
+      let candidates = find_candidates(&drinks, &query, credits, preferred_machine.as_deref());
+      let candidate = choose(&query, candidates)?;
+      picked = Some(candidate.describe());
+      DropPlan {
+        machine: candidate.machine.clone(),
+        slot: candidate.slot.clone(),
+        balance: credits,
+      }
+    }
+  };
+  if !options.force {
+    plan.validate()?;
+  }
+  let new_balance = if options.dry_run {
+    plan.new_balance()
+  } else {
+    if options.confirm {
+      confirm(&plan)?;
+    } else if let (Some(picked), OutputFormat::Text) = (picked, format) {
+      eprintln!("Dropping {}", picked);
+    }
+    match api.drop(plan.machine.name.clone(), plan.slot.number) {
+      Ok(balance) => balance,
+      Err(err @ APIError::DropOutcomeUnknown(_)) => {
+        offer_balance_check(api, &plan);
+        return Err(err);
+      }
+      Err(err) => return Err(err),
+    }
+  };
+  output::print(
+    out,
+    format,
+    &DropOutput {
+      machine: plan.machine.name,
+      slot: plan.slot.number,
+      item: plan.slot.item,
+      new_balance,
+      dry_run: options.dry_run,
+    },
+  );
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clink::backend::MemoryBackend;
+
+  const OPTIONS: DropOptions = DropOptions {
+    force: false,
+    confirm: false,
+    dry_run: false,
+  };
+
+  fn item(query: &str) -> DropTarget {
+    DropTarget::Item {
+      query: query.to_string(),
+      machine: None,
+    }
+  }
+
+  #[test]
+  fn drops_by_name() {
+    let backend = MemoryBackend::sample();
+    let mut out = Vec::new();
+    // Coke Zero matches too, but it's in an offline machine
+    drop(
+      &backend,
+      &mut out,
+      item("coke"),
+      None,
+      OPTIONS,
+      OutputFormat::Text,
+    )
+    .unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "Item dropped! Your new balance is 150\n"
+    );
+    assert_eq!(backend.credits(), 150);
+  }
+
+  #[test]
+  fn dry_run_changes_nothing() {
+    let backend = MemoryBackend::sample();
+    let mut out = Vec::new();
+    let options = DropOptions {
+      dry_run: true,
+      ..OPTIONS
+    };
+    let target = DropTarget::Slot {
+      machine: "bigdrink".to_string(),
+      slot: 2,
+    };
+    drop(
+      &backend,
+      &mut out,
+      target,
+      None,
+      options,
+      OutputFormat::Ndjson,
+    )
+    .unwrap();
+    let record: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(record["data"]["item"], "Sprite");
+    assert_eq!(record["data"]["new_balance"], 150);
+    assert_eq!(record["data"]["dry_run"], true);
+    assert_eq!(backend.credits(), 200);
+  }
+
+  #[test]
+  fn refuses_before_the_server_does() {
+    let backend = MemoryBackend::sample();
+    let target = DropTarget::Slot {
+      machine: "bigdrink".to_string(),
+      slot: 3,
+    };
+    let result = drop(
+      &backend,
+      &mut Vec::new(),
+      target,
+      None,
+      OPTIONS,
+      OutputFormat::Text,
+    );
+    assert!(matches!(result, Err(APIError::SlotEmpty(_, 3))));
+    assert!(matches!(
+      drop(
+        &backend,
+        &mut Vec::new(),
+        item("pepsi"),
+        None,
+        OPTIONS,
+        OutputFormat::Text
+      ),
+      Err(APIError::ItemNotFound(_))
+    ));
+  }
+
+  #[test]
+  fn says_why_nothing_matching_can_drop() {
+    // Coke is too expensive and Coke Zero is offline
+    let backend = MemoryBackend::new(MemoryBackend::sample().drinks(), 10);
+    let result = drop(
+      &backend,
+      &mut Vec::new(),
+      item("coke"),
+      None,
+      OPTIONS,
+      OutputFormat::Text,
+    );
+    assert!(matches!(result, Err(APIError::InsufficientCredits { .. })));
+  }
+}
@@ -1,21 +1,126 @@
-use crate::api::{APIError, API};
+use crate::output::{self, Output, OutputFormat};
+use clink::api::{APIError, DrinkList};
+use clink::backend::DrinkBackend;
+use serde::Serialize;
+use std::io::Write;
 
-pub fn list(api: &mut API, machine: Option<String>) -> Result<(), APIError> {
-  let drinks = api.get_status_for_machine(machine.as_deref())?;
+/// One slot per row, with the machine it's in
+#[derive(Serialize)]
+pub struct SlotRecord {
+  machine: String,
+  machine_display_name: String,
+  machine_online: bool,
+  slot: u8,
+  item_id: u64,
+  item: String,
+  price: u64,
+  active: bool,
+  empty: bool,
+  count: Option<u64>,
+}
+
+impl Output for DrinkList {
+  type Record = SlotRecord;
+
+  fn command(&self) -> &'static str {
+    "list"
+  }
 
-  for machine in drinks.machines {
-    println!();
-    let subject_line = format!("{} ({})", machine.display_name, machine.name);
-    println!("{}", &subject_line);
-    println!("{}", "=".repeat(subject_line.len()));
-    for slot in machine.slots {
-      let item = slot.item;
-      print!("{}. {} ({} Credits)", slot.number, item.name, item.price);
-      if slot.empty {
-        print!(" [EMPTY]");
+  fn text(&self) -> String {
+    let mut text = String::new();
+    for machine in &self.machines {
+      text.push('\n');
+      let subject_line = format!("{} ({})", machine.display_name, machine.name);
+      text.push_str(&format!("{}\n", &subject_line));
+      text.push_str(&format!("{}\n", "=".repeat(subject_line.len())));
+      for slot in &machine.slots {
+        let item = &slot.item;
+        text.push_str(&format!(
+          "{}. {} ({} Credits)",
+          slot.number, item.name, item.price
+        ));
+        if slot.empty {
+          text.push_str(" [EMPTY]");
+        }
+        text.push('\n');
       }
-      println!();
     }
+    text
+  }
+
+  fn records(&self) -> Vec<SlotRecord> {
+    self
+      .machines
+      .iter()
+      .flat_map(|machine| {
+        machine.slots.iter().map(|slot| SlotRecord {
+          machine: machine.name.clone(),
+          machine_display_name: machine.display_name.clone(),
+          machine_online: machine.is_online,
+          slot: slot.number,
+          item_id: slot.item.id,
+          item: slot.item.name.clone(),
+          price: slot.item.price,
+          active: slot.active,
+          empty: slot.empty,
+          count: slot.count,
+        })
+      })
+      .collect()
   }
+}
+
+pub fn list(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  machine: Option<String>,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  let drinks = api.get_status_for_machine(machine.as_deref())?;
+  output::print(out, format, &drinks);
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clink::backend::MemoryBackend;
+
+  #[test]
+  fn lists_one_machine() {
+    let mut out = Vec::new();
+    list(
+      &MemoryBackend::sample(),
+      &mut out,
+      Some("bigdrink".to_string()),
+      OutputFormat::Text,
+    )
+    .unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "\nBig Drink (bigdrink)\n====================\n\
+       1. Coke (50 Credits)\n\
+       2. Sprite (50 Credits)\n\
+       3. Dr. Pepper (75 Credits) [EMPTY]\n"
+    );
+  }
+
+  #[test]
+  fn lists_every_slot_as_csv() {
+    let mut out = Vec::new();
+    list(&MemoryBackend::sample(), &mut out, None, OutputFormat::Csv).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let mut lines = out.lines();
+    assert_eq!(
+      lines.next(),
+      Some(
+        "machine,machine_display_name,machine_online,slot,item_id,item,price,active,empty,count"
+      )
+    );
+    assert_eq!(
+      lines.next(),
+      Some("bigdrink,Big Drink,true,1,1,Coke,50,true,false,")
+    );
+    assert_eq!(lines.count(), 3);
+  }
+}
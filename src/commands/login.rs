@@ -0,0 +1,77 @@
+use crate::output::{self, Output, OutputFormat};
+use clink::api::{APIError, DeviceCode};
+use clink::backend::DrinkBackend;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde::Serialize;
+use std::io::{self, IsTerminal, Write};
+
+#[derive(Serialize, Clone)]
+pub struct LoginOutput {
+  username: String,
+}
+
+impl Output for LoginOutput {
+  type Record = LoginOutput;
+
+  fn command(&self) -> &'static str {
+    "login"
+  }
+
+  fn text(&self) -> String {
+    format!("Logged in as {}\n", self.username)
+  }
+
+  fn records(&self) -> Vec<LoginOutput> {
+    vec![self.clone()]
+  }
+}
+
+/// What to do with the device code. Goes on stderr, so `--output json` still
+/// only prints JSON. The QR code only shows up on a terminal.
+fn device_prompt(code: &DeviceCode, qr: bool) -> String {
+  let mut prompt = format!(
+    "To log in, open {} and enter {}\n",
+    code.verification_uri, code.user_code
+  );
+  let url = code
+    .verification_uri_complete
+    .as_ref()
+    .unwrap_or(&code.verification_uri);
+  if let (true, Ok(qr)) = (qr, QrCode::new(url)) {
+    let image = qr
+      .render::<Dense1x2>()
+      .dark_color(Dense1x2::Light)
+      .light_color(Dense1x2::Dark)
+      .build();
+    prompt.push_str(&format!("or scan this:\n{}\n", image));
+  }
+  prompt.push_str("Waiting for you to approve it...\n");
+  prompt
+}
+
+pub fn login(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  force_password: bool,
+  device: bool,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  let user = match device {
+    true => api.login_device(&mut |code| {
+      let stderr = io::stderr();
+      let qr = stderr.is_terminal();
+      write!(stderr.lock(), "{}", device_prompt(code, qr)).ok();
+    })?,
+    false => api.login(force_password)?,
+  };
+  output::print(
+    out,
+    format,
+    &LoginOutput {
+      username: user.preferred_username,
+    },
+  );
+
+  Ok(())
+}
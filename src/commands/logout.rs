@@ -0,0 +1,57 @@
+use crate::output::{self, Output, OutputFormat};
+use clink::api::APIError;
+use clink::backend::DrinkBackend;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Serialize, Clone)]
+pub struct LogoutOutput {
+  /// Credential cache whose ticket we destroyed
+  destroyed_ccache: Option<String>,
+  /// Credential cache we were asked to destroy but didn't, since it's not ours
+  kept_ccache: Option<String>,
+}
+
+impl Output for LogoutOutput {
+  type Record = LogoutOutput;
+
+  fn command(&self) -> &'static str {
+    "logout"
+  }
+
+  fn text(&self) -> String {
+    let mut text = "Logged out\n".to_string();
+    if let Some(ccache) = &self.destroyed_ccache {
+      text.push_str(&format!("Destroyed the Kerberos ticket in {}\n", ccache));
+    } else if let Some(ccache) = &self.kept_ccache {
+      text.push_str(&format!(
+        "Kept the Kerberos ticket in {}, clink didn't create it (use `kdestroy`)\n",
+        ccache
+      ));
+    }
+    text
+  }
+
+  fn records(&self) -> Vec<LogoutOutput> {
+    vec![self.clone()]
+  }
+}
+
+pub fn logout(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  destroy_ticket: bool,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  let logout = api.logout(destroy_ticket)?;
+  output::print(
+    out,
+    format,
+    &LogoutOutput {
+      destroyed_ccache: logout.destroyed_ccache,
+      kept_ccache: logout.kept_ccache,
+    },
+  );
+
+  Ok(())
+}
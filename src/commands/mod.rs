@@ -1,4 +1,10 @@
+pub mod config;
 pub mod credits;
+pub mod doctor;
 pub mod drop;
 pub mod list;
+pub mod login;
+pub mod logout;
+pub mod profile;
 pub mod token;
+pub mod whoami;
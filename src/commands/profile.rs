@@ -0,0 +1,49 @@
+use crate::config::{Config, ConfigError};
+
+/// Prints every profile and its API URL, with a `*` next to the one in use
+pub fn list(selected: Option<&str>) -> Result<(), ConfigError> {
+  let table = Config::load_table()?;
+  let active = selected.or_else(|| table.get("default_profile").and_then(|name| name.as_str()));
+  let profiles = match table
+    .get("profile")
+    .and_then(|profiles| profiles.as_table())
+  {
+    Some(profiles) if !profiles.is_empty() => profiles,
+    _ => {
+      println!("No profiles yet, add one with `clink config set profile.<name>.api <URL>`");
+      return Ok(());
+    }
+  };
+  let width = profiles.keys().map(|name| name.len()).max().unwrap_or(0);
+  for (name, profile) in profiles {
+    let marker = match Some(name.as_str()) == active {
+      true => '*',
+      false => ' ',
+    };
+    let api = profile
+      .get("api")
+      .and_then(|api| api.as_str())
+      .unwrap_or("");
+    let line = format!("{} {:width$}  {}", marker, name, api, width = width);
+    println!("{}", line.trim_end());
+  }
+  Ok(())
+}
+
+/// Makes `name` the profile clink uses without `--profile`, or goes back to
+/// no profile with `None`
+pub fn use_profile(name: Option<&str>) -> Result<(), ConfigError> {
+  match name {
+    Some(name) => {
+      // Make sure it's there, a typo here would break every other command
+      Config::load(Some(name))?;
+      let path = Config::set_value("default_profile", name.into())?;
+      println!("Using {} from now on (set in {})", name, path.display());
+    }
+    None => {
+      let path = Config::unset("default_profile")?;
+      println!("Not using a profile by default (set in {})", path.display());
+    }
+  }
+  Ok(())
+}
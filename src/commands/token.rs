@@ -1,7 +1,37 @@
-use crate::api::{APIError, API};
+use crate::output::{self, Output, OutputFormat};
+use clink::api::APIError;
+use clink::backend::DrinkBackend;
+use serde::Serialize;
+use std::io::Write;
 
-pub fn token(api: &mut API) -> Result<(), APIError> {
-  println!("{}", api.get_token()?);
+#[derive(Serialize, Clone)]
+pub struct TokenOutput {
+  token: String,
+}
+
+impl Output for TokenOutput {
+  type Record = TokenOutput;
+
+  fn command(&self) -> &'static str {
+    "token"
+  }
+
+  fn text(&self) -> String {
+    format!("{}\n", self.token)
+  }
+
+  fn records(&self) -> Vec<TokenOutput> {
+    vec![self.clone()]
+  }
+}
+
+pub fn token(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  let token = api.get_token()?;
+  output::print(out, format, &TokenOutput { token });
 
   Ok(())
 }
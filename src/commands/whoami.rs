@@ -0,0 +1,122 @@
+use crate::output::{self, Output, OutputFormat};
+use clink::api::APIError;
+use clink::backend::DrinkBackend;
+use clink::token_cache::now;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Serialize, Clone)]
+pub struct WhoAmI {
+  username: String,
+  name: Option<String>,
+  groups: Vec<String>,
+  /// Seconds since the unix epoch
+  token_expires_at: Option<u64>,
+  /// Seconds since the unix epoch
+  ticket_expires_at: Option<u64>,
+}
+
+/// [`WhoAmI`] with the groups squashed into one space-separated column
+#[derive(Serialize)]
+pub struct WhoAmIRecord {
+  username: String,
+  name: Option<String>,
+  groups: String,
+  token_expires_at: Option<u64>,
+  ticket_expires_at: Option<u64>,
+}
+
+impl Output for WhoAmI {
+  type Record = WhoAmIRecord;
+
+  fn command(&self) -> &'static str {
+    "whoami"
+  }
+
+  fn text(&self) -> String {
+    let now = now();
+    let mut text = match &self.name {
+      Some(name) => format!("{} ({})\n", self.username, name),
+      None => format!("{}\n", self.username),
+    };
+    if !self.groups.is_empty() {
+      text.push_str(&format!("Groups: {}\n", self.groups.join(", ")));
+    }
+    text.push_str(&format!(
+      "Token: {}\n",
+      describe_expiry(self.token_expires_at, now)
+    ));
+    text.push_str(&format!(
+      "Kerberos ticket: {}\n",
+      describe_expiry(self.ticket_expires_at, now)
+    ));
+    text
+  }
+
+  fn records(&self) -> Vec<WhoAmIRecord> {
+    vec![WhoAmIRecord {
+      username: self.username.clone(),
+      name: self.name.clone(),
+      groups: self.groups.join(" "),
+      token_expires_at: self.token_expires_at,
+      ticket_expires_at: self.ticket_expires_at,
+    }]
+  }
+}
+
+/// "expires in 4h 12m", rounded down to the two biggest units
+pub fn describe_expiry(expires_at: Option<u64>, now: u64) -> String {
+  let expires_at = match expires_at {
+    Some(expires_at) => expires_at,
+    None => return "unknown expiry".to_string(),
+  };
+  if expires_at <= now {
+    return "expired".to_string();
+  }
+  let left = expires_at - now;
+  let (hours, minutes, seconds) = (left / 3600, left / 60 % 60, left % 60);
+  let left = match (hours, minutes) {
+    (0, 0) => format!("{}s", seconds),
+    (0, _) => format!("{}m {}s", minutes, seconds),
+    _ => format!("{}h {}m", hours, minutes),
+  };
+  format!("expires in {}", left)
+}
+
+pub fn whoami(
+  api: &impl DrinkBackend,
+  out: &mut impl Write,
+  format: OutputFormat,
+) -> Result<(), APIError> {
+  let user = api.get_user_info()?;
+  output::print(
+    out,
+    format,
+    &WhoAmI {
+      username: user.preferred_username,
+      name: user.name,
+      groups: user.groups,
+      token_expires_at: api.token_expires_at(),
+      ticket_expires_at: api.ticket_expires_at(),
+    },
+  );
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn describes_expiry() {
+    assert_eq!(describe_expiry(None, 1000), "unknown expiry");
+    assert_eq!(describe_expiry(Some(900), 1000), "expired");
+    assert_eq!(describe_expiry(Some(1042), 1000), "expires in 42s");
+    assert_eq!(describe_expiry(Some(1299), 1000), "expires in 4m 59s");
+    assert_eq!(
+      describe_expiry(Some(1000 + 36000 + 61), 1000),
+      "expires in 10h 1m"
+    );
+  }
+}
@@ -0,0 +1,392 @@
+use crate::output::OutputFormat;
+use crate::ui::keys::KeyBinding;
+use clink::api::{
+  RetryPolicy, SsoConfig, DEFAULT_API_URL, DEFAULT_CONNECT_TIMEOUT, DEFAULT_TIMEOUT,
+};
+use http::Uri;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use users::get_current_username;
+
+/// Floor-wide defaults, shipped on the user machines
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/clink/config.toml";
+
+/// Everything that can go in a config file. Layers are applied in order:
+/// built-in defaults, then `/etc/clink/config.toml`, then
+/// `$XDG_CONFIG_HOME/clink/config.toml`. Environment variables and flags are
+/// handled by clap and win over all of them.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// Drink API base URL
+  pub api: Option<String>,
+  /// Machine `clink list` shows when none is given
+  pub default_machine: Option<String>,
+  /// How commands print their results
+  pub output: Option<OutputFormat>,
+  /// Ask before dropping a drink
+  pub confirm_drops: Option<bool>,
+  /// Who to get a Kerberos ticket for
+  pub username: Option<String>,
+  /// Profile to use when there's no `--profile`
+  pub default_profile: Option<String>,
+  pub auth: AuthSection,
+  pub sso: SsoSection,
+  pub http: HttpSection,
+  pub tui: TuiSection,
+  /// Named sets of settings, laid over the ones above when selected
+  pub profile: BTreeMap<String, Profile>,
+  /// Which profile got laid over the rest, if any
+  #[serde(skip)]
+  pub active_profile: Option<String>,
+  /// Whether that profile is for someone other than you, going by `username`
+  #[serde(skip)]
+  pub switches_user: bool,
+}
+
+/// What a `[profile.<name>]` can override
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+  pub api: Option<String>,
+  pub default_machine: Option<String>,
+  pub username: Option<String>,
+  pub auth: AuthSection,
+  pub sso: SsoSection,
+  pub http: HttpSection,
+}
+
+/// How clink gets the tokens it sends to the drink API
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+  /// Trade your Kerberos ticket for a token at SSO
+  Spnego,
+  /// Send an API key or token you already have
+  Token,
+  /// Trade an OpenID Connect refresh token for tokens at SSO
+  RefreshToken,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+  /// [default: `token` if there's a `CLINK_TOKEN`, `spnego` otherwise]
+  pub method: Option<AuthMethod>,
+  /// For `method = "token"`, `CLINK_TOKEN` wins over it
+  pub token: Option<String>,
+  /// For `method = "refresh-token"`, `CLINK_REFRESH_TOKEN` wins over it
+  pub refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsoSection {
+  pub url: Option<String>,
+  pub realm: Option<String>,
+  pub client_id: Option<String>,
+  pub kerberos_realm: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSection {
+  /// Seconds a request may take
+  pub timeout: Option<u64>,
+  /// Seconds to wait for a connection
+  pub connect_timeout: Option<u64>,
+  /// Like `http://proxy.example:3128`
+  #[serde(deserialize_with = "uri")]
+  pub proxy: Option<Uri>,
+  /// PEM file with the certificate authorities to trust
+  pub ca_bundle: Option<PathBuf>,
+  /// Times to retry a GET when the network flakes out
+  pub retries: Option<u32>,
+  /// Longest to wait between retries, in milliseconds
+  pub max_retry_delay_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuiSection {
+  /// Path to a cursive theme file
+  pub theme: Option<PathBuf>,
+  pub keys: KeySection,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeySection {
+  pub quit: Option<KeyBinding>,
+  pub back: Option<KeyBinding>,
+  pub select: Option<KeyBinding>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  IOError(PathBuf, io::Error),
+  ParseError(PathBuf, toml::de::Error),
+  EditError(PathBuf, toml_edit::TomlError),
+  InvalidKey(String),
+  InvalidValue(String, toml::de::Error),
+  NotSet(String),
+  UnknownProfile(String),
+  NoConfigDir,
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::IOError(path, err) => write!(f, "Couldn't read {}: {}", path.display(), err),
+      ConfigError::ParseError(path, err) => {
+        write!(f, "Couldn't parse {}: {}", path.display(), err)
+      }
+      ConfigError::EditError(path, err) => {
+        write!(f, "Couldn't parse {}: {}", path.display(), err)
+      }
+      ConfigError::InvalidKey(key) => write!(f, "{} is not a config option", key),
+      ConfigError::InvalidValue(key, err) => write!(f, "Can't set {}: {}", key, err.message()),
+      ConfigError::NotSet(key) => write!(f, "{} is not set", key),
+      ConfigError::UnknownProfile(name) => {
+        write!(f, "There's no [profile.{}] in your config", name)
+      }
+      ConfigError::NoConfigDir => write!(f, "Couldn't figure out where your config lives"),
+    }
+  }
+}
+
+impl Config {
+  /// `$XDG_CONFIG_HOME/clink/config.toml`
+  pub fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("clink").join("config.toml"))
+  }
+
+  /// Loads every layer, then lays `profile` (or `default_profile`, if no
+  /// profile was asked for) over the result
+  pub fn load(profile: Option<&str>) -> Result<Config, ConfigError> {
+    let mut table = Config::load_table()?;
+    let name = profile.map(str::to_string).or_else(|| {
+      table
+        .get("default_profile")
+        .and_then(|name| name.as_str())
+        .map(str::to_string)
+    });
+    // Who you are without the profile, the same way kinit would decide
+    let you = table
+      .get("username")
+      .and_then(|username| username.as_str())
+      .map(str::to_string)
+      .or_else(|| get_current_username().and_then(|username| username.into_string().ok()));
+    if let Some(name) = &name {
+      let overlay = table
+        .get("profile")
+        .and_then(|profiles| profiles.get(name))
+        .and_then(|profile| profile.as_table())
+        .cloned()
+        .ok_or_else(|| ConfigError::UnknownProfile(name.clone()))?;
+      merge(&mut table, overlay);
+    }
+    let mut config = Config::from_table(table)?;
+    config.switches_user = name.is_some() && config.username.is_some() && config.username != you;
+    config.active_profile = name;
+    Ok(config)
+  }
+
+  /// All of the config file layers merged into one table
+  pub fn load_table() -> Result<toml::Table, ConfigError> {
+    let mut table = Config::defaults();
+    let paths = [Some(PathBuf::from(SYSTEM_CONFIG_PATH)), Config::path()];
+    for path in paths.iter().flatten() {
+      if let Some(layer) = read_table(path)? {
+        merge(&mut table, layer);
+      }
+    }
+    Ok(table)
+  }
+
+  fn from_table(table: toml::Table) -> Result<Config, ConfigError> {
+    // Every layer has already been checked by read_table, so this only fails
+    // if the defaults are broken
+    Config::deserialize(toml::Value::Table(table))
+      .map_err(|err| ConfigError::ParseError(PathBuf::from("<defaults>"), err))
+  }
+
+  fn defaults() -> toml::Table {
+    let sso = SsoConfig::default();
+    let mut table = toml::Table::new();
+    table.insert("api".to_string(), DEFAULT_API_URL.into());
+    table.insert("output".to_string(), "text".into());
+    table.insert("confirm_drops".to_string(), true.into());
+
+    let mut sso_table = toml::Table::new();
+    sso_table.insert("url".to_string(), sso.url.into());
+    sso_table.insert("realm".to_string(), sso.realm.into());
+    sso_table.insert("client_id".to_string(), sso.client_id.into());
+    sso_table.insert("kerberos_realm".to_string(), sso.kerberos_realm.into());
+    table.insert("sso".to_string(), sso_table.into());
+
+    let mut http_table = toml::Table::new();
+    http_table.insert(
+      "timeout".to_string(),
+      (DEFAULT_TIMEOUT.as_secs() as i64).into(),
+    );
+    http_table.insert(
+      "connect_timeout".to_string(),
+      (DEFAULT_CONNECT_TIMEOUT.as_secs() as i64).into(),
+    );
+    let retry = RetryPolicy::default();
+    http_table.insert("retries".to_string(), (retry.max_retries as i64).into());
+    http_table.insert(
+      "max_retry_delay_ms".to_string(),
+      (retry.max_delay.as_millis() as i64).into(),
+    );
+    table.insert("http".to_string(), http_table.into());
+
+    let mut keys_table = toml::Table::new();
+    keys_table.insert("quit".to_string(), "q".into());
+    keys_table.insert("back".to_string(), "Left".into());
+    keys_table.insert("select".to_string(), "Right".into());
+    let mut tui_table = toml::Table::new();
+    tui_table.insert("keys".to_string(), keys_table.into());
+    table.insert("tui".to_string(), tui_table.into());
+
+    table
+  }
+
+  /// Sets `key` (dotted, like `sso.realm`) in the user's config file, keeping
+  /// whatever comments and formatting are already there. `value` is parsed as
+  /// TOML if it can be, otherwise it's taken as a string.
+  pub fn set(key: &str, value: &str) -> Result<PathBuf, ConfigError> {
+    let value = value
+      .parse::<toml_edit::Value>()
+      .unwrap_or_else(|_| value.into());
+    Config::set_value(key, value)
+  }
+
+  /// [`Config::set`], with a value that's already TOML
+  pub fn set_value(key: &str, value: toml_edit::Value) -> Result<PathBuf, ConfigError> {
+    Config::edit(key, |document| {
+      let mut parts: Vec<&str> = key.split('.').collect();
+      let last = parts
+        .pop()
+        .ok_or_else(|| ConfigError::InvalidKey(key.to_string()))?;
+      let mut table = document.as_table_mut();
+      for part in parts {
+        table = table
+          .entry(part)
+          .or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            table.into()
+          })
+          .as_table_mut()
+          .ok_or_else(|| ConfigError::InvalidKey(key.to_string()))?;
+      }
+      table.insert(last, toml_edit::value(value));
+      Ok(())
+    })
+  }
+
+  /// Takes a top-level `key` out of the user's config file, if it's there
+  pub fn unset(key: &str) -> Result<PathBuf, ConfigError> {
+    Config::edit(key, |document| {
+      document.remove(key);
+      Ok(())
+    })
+  }
+
+  /// Edits the user's config file with `edit`, refusing to write it if the
+  /// result isn't a valid config
+  fn edit(
+    key: &str,
+    edit: impl FnOnce(&mut toml_edit::DocumentMut) -> Result<(), ConfigError>,
+  ) -> Result<PathBuf, ConfigError> {
+    let path = Config::path().ok_or(ConfigError::NoConfigDir)?;
+    let contents = match fs::read_to_string(&path) {
+      Ok(contents) => contents,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+      Err(err) => return Err(ConfigError::IOError(path, err)),
+    };
+    let mut document = contents
+      .parse::<toml_edit::DocumentMut>()
+      .map_err(|err| ConfigError::EditError(path.clone(), err))?;
+    edit(&mut document)?;
+
+    // Don't write out something we won't be able to read back in
+    let contents = document.to_string();
+    toml::from_str::<Config>(&contents)
+      .map_err(|err| ConfigError::InvalidValue(key.to_string(), err))?;
+
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).map_err(|err| ConfigError::IOError(path.clone(), err))?;
+    }
+    fs::write(&path, contents).map_err(|err| ConfigError::IOError(path.clone(), err))?;
+    Ok(path)
+  }
+}
+
+fn uri<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Uri>, D::Error> {
+  let uri = String::deserialize(deserializer)?;
+  uri.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Looks up a dotted key (like `sso.realm`) in a config table
+pub fn get<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+  let mut parts = key.split('.');
+  let mut value = table.get(parts.next()?)?;
+  for part in parts {
+    value = value.as_table()?.get(part)?;
+  }
+  Some(value)
+}
+
+/// Flattens a table into `(dotted.key, value)` pairs
+pub fn flatten(table: &toml::Table) -> Vec<(String, &toml::Value)> {
+  let mut entries = Vec::new();
+  for (key, value) in table {
+    match value {
+      toml::Value::Table(inner) => {
+        for (inner_key, inner_value) in flatten(inner) {
+          entries.push((format!("{}.{}", key, inner_key), inner_value));
+        }
+      }
+      value => entries.push((key.clone(), value)),
+    }
+  }
+  entries
+}
+
+/// Reads a single layer, making sure it's valid on its own so errors point at
+/// the right file. A missing file is the same as an empty one.
+fn read_table(path: &Path) -> Result<Option<toml::Table>, ConfigError> {
+  let contents = match fs::read_to_string(path) {
+    Ok(contents) => contents,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(ConfigError::IOError(path.to_path_buf(), err)),
+  };
+  toml::from_str::<Config>(&contents).map_err(|err| ConfigError::ParseError(path.into(), err))?;
+  toml::from_str(&contents)
+    .map(Some)
+    .map_err(|err| ConfigError::ParseError(path.into(), err))
+}
+
+/// Recursively merges `overlay` into `base`, with `overlay` winning
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+  for (key, value) in overlay {
+    match (base.get_mut(&key), value) {
+      (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+        merge(base_table, overlay_table)
+      }
+      (_, value) => {
+        base.insert(key, value);
+      }
+    }
+  }
+}
@@ -0,0 +1,575 @@
+//! Getting a Kerberos ticket from a password or keytab. With the `krb5` feature this
+//! talks to libkrb5 directly; without it, or if the library can't start up,
+//! it runs `kinit` instead. Either way the ticket lands in the default
+//! credential cache (`KRB5CCNAME`), where SPNEGO will find it.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Why we couldn't get a ticket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginError {
+  /// The KDC didn't accept the password
+  WrongPassword,
+  /// The KDC has never heard of this principal
+  UnknownPrincipal(String),
+  /// The password was right, but it has to be changed first
+  PasswordExpired,
+  /// Couldn't find or reach a KDC for the realm
+  KdcUnreachable(String),
+  /// Our clock is too far from the KDC's
+  ClockSkew,
+  /// No libkrb5, and no `kinit` on the `PATH` either
+  NoKinit,
+  /// Anything else, with whatever Kerberos said about it
+  Other(String),
+}
+
+impl LoginError {
+  /// Whether asking for the password again could help
+  pub fn can_retry(&self) -> bool {
+    matches!(self, LoginError::WrongPassword)
+  }
+}
+
+impl fmt::Display for LoginError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoginError::WrongPassword => write!(f, "Password incorrect"),
+      LoginError::UnknownPrincipal(principal) => {
+        write!(f, "{} isn't a Kerberos principal", principal)
+      }
+      LoginError::PasswordExpired => write!(
+        f,
+        "Your password has expired, change it with `kpasswd` first"
+      ),
+      LoginError::KdcUnreachable(realm) => {
+        write!(f, "Couldn't reach a Kerberos server for {}", realm)
+      }
+      LoginError::ClockSkew => write!(
+        f,
+        "Your clock is too far off from the Kerberos server's, check the time"
+      ),
+      LoginError::NoKinit => write!(f, "Couldn't find `kinit`, is Kerberos installed?"),
+      LoginError::Other(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for LoginError {}
+
+/// Gets a ticket for `principal` (`user@REALM`) and stores it in the default
+/// credential cache
+pub fn login(principal: &str, password: &str) -> Result<(), LoginError> {
+  #[cfg(feature = "krb5")]
+  match krb5::login(principal, krb5::Key::Password(password)) {
+    Err(krb5::Unavailable) => {}
+    Ok(result) => return result,
+  }
+  kinit(principal, &[], Some(password))
+}
+
+/// Like [`login`], but with the keys in a keytab instead of a password
+pub fn login_keytab(principal: &str, keytab: &Path) -> Result<(), LoginError> {
+  #[cfg(feature = "krb5")]
+  match krb5::login(principal, krb5::Key::Keytab(keytab)) {
+    Err(krb5::Unavailable) => {}
+    Ok(result) => return result,
+  }
+  let keytab = keytab.as_os_str();
+  kinit(principal, &["-k".as_ref(), "-t".as_ref(), keytab], None)
+}
+
+/// Name of the default credential cache (like `FILE:/tmp/krb5cc_1000`), if
+/// we can tell
+pub fn ccache_name() -> Option<String> {
+  #[cfg(feature = "krb5")]
+  if let Ok(name) = krb5::ccache_name() {
+    return name;
+  }
+  std::env::var("KRB5CCNAME").ok()
+}
+
+/// Makes the credential cache at `path` the default one, for us and for
+/// SPNEGO alike, by pointing `KRB5CCNAME` at it. Call it before anything else
+/// touches Kerberos (or starts a thread).
+pub fn use_ccache(path: &Path) -> io::Result<()> {
+  // Even if there's nowhere to put it, so we never fall back to another one
+  std::env::set_var("KRB5CCNAME", format!("FILE:{}", path.display()));
+  match path.parent() {
+    Some(dir) => fs::create_dir_all(dir),
+    None => Ok(()),
+  }
+}
+
+/// What's in the default credential cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+  /// Who the cache belongs to, like `user@CSH.RIT.EDU`
+  pub principal: String,
+  /// When the ticket-granting ticket expires, in seconds since the unix
+  /// epoch. `None` if there isn't one.
+  pub expires_at: Option<u64>,
+}
+
+impl Ticket {
+  pub fn realm(&self) -> &str {
+    realm(&self.principal)
+  }
+}
+
+/// Reads the default credential cache. Always `None` without the `krb5`
+/// feature, or if there's no cache.
+pub fn ticket() -> Option<Ticket> {
+  #[cfg(feature = "krb5")]
+  if let Ok(ticket) = krb5::ticket() {
+    return ticket;
+  }
+  None
+}
+
+/// Destroys the default credential cache, like `kdestroy`
+pub fn destroy() -> Result<(), LoginError> {
+  #[cfg(feature = "krb5")]
+  match krb5::destroy() {
+    Err(krb5::Unavailable) => {}
+    Ok(result) => return result,
+  }
+  let output = Command::new("kdestroy")
+    .stdin(Stdio::null())
+    .output()
+    .map_err(|err| match err.kind() {
+      io::ErrorKind::NotFound => LoginError::NoKinit,
+      _ => LoginError::Other(format!("Couldn't run kdestroy: {}", err)),
+    })?;
+  match output.status.success() {
+    true => Ok(()),
+    false => Err(LoginError::Other(
+      String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    )),
+  }
+}
+
+/// Runs `kinit`, feeding it the password (if there is one) on stdin
+fn kinit(principal: &str, args: &[&OsStr], password: Option<&str>) -> Result<(), LoginError> {
+  let mut process = Command::new("kinit")
+    .args(args)
+    .arg(principal)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| match err.kind() {
+      io::ErrorKind::NotFound => LoginError::NoKinit,
+      _ => LoginError::Other(format!("Couldn't run kinit: {}", err)),
+    })?;
+  if let (Some(mut stdin), Some(password)) = (process.stdin.take(), password) {
+    // If kinit already quit, the error's on stderr
+    stdin.write_all(password.as_bytes()).ok();
+  }
+  let mut stderr = String::new();
+  if let Some(mut pipe) = process.stderr.take() {
+    pipe.read_to_string(&mut stderr).ok();
+  }
+  let status = process
+    .wait()
+    .map_err(|err| LoginError::Other(format!("Couldn't run kinit: {}", err)))?;
+  match status.success() {
+    true => Ok(()),
+    false => Err(classify_kinit(principal, stderr.trim())),
+  }
+}
+
+/// Works out what went wrong from what `kinit` printed. These are MIT's
+/// messages for the same codes `krb5::classify` looks at.
+fn classify_kinit(principal: &str, stderr: &str) -> LoginError {
+  if stderr.contains("Password incorrect") || stderr.contains("Preauthentication failed") {
+    LoginError::WrongPassword
+  } else if stderr.contains("not found in Kerberos database") {
+    LoginError::UnknownPrincipal(principal.to_string())
+  } else if stderr.contains("Password has expired") {
+    LoginError::PasswordExpired
+  } else if stderr.contains("Cannot contact any KDC")
+    || stderr.contains("Cannot find KDC")
+    || stderr.contains("Cannot resolve network address")
+  {
+    LoginError::KdcUnreachable(realm(principal).to_string())
+  } else if stderr.contains("Clock skew too great") {
+    LoginError::ClockSkew
+  } else if stderr.is_empty() {
+    LoginError::Other("kinit failed".to_string())
+  } else {
+    LoginError::Other(stderr.to_string())
+  }
+}
+
+fn realm(principal: &str) -> &str {
+  principal
+    .rsplit_once('@')
+    .map(|(_, realm)| realm)
+    .unwrap_or(principal)
+}
+
+#[cfg(feature = "krb5")]
+mod krb5 {
+  //! Just enough of libkrb5's API to turn a password into a ticket. Anything
+  //! that depends on struct layouts or error codes is in `krb5_shim.c`, built
+  //! against the installed `krb5.h`, so here everything's an opaque pointer.
+
+  use super::{realm, LoginError, Ticket};
+  use std::ffi::{CStr, CString};
+  use std::os::raw::{c_char, c_int, c_void};
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::Path;
+  use std::ptr;
+
+  pub type ErrorCode = i32;
+  type Context = *mut c_void;
+  type Principal = *mut c_void;
+  type CCache = *mut c_void;
+  type InitCredsOpt = *mut c_void;
+  type Keytab = *mut c_void;
+
+  #[link(name = "krb5")]
+  extern "C" {
+    fn krb5_init_context(context: *mut Context) -> ErrorCode;
+    fn krb5_free_context(context: Context);
+    fn krb5_parse_name(
+      context: Context,
+      name: *const c_char,
+      principal: *mut Principal,
+    ) -> ErrorCode;
+    fn krb5_free_principal(context: Context, principal: Principal);
+    fn krb5_cc_default(context: Context, ccache: *mut CCache) -> ErrorCode;
+    fn krb5_cc_close(context: Context, ccache: CCache) -> ErrorCode;
+    fn krb5_cc_destroy(context: Context, ccache: CCache) -> ErrorCode;
+    fn krb5_cc_default_name(context: Context) -> *const c_char;
+    fn krb5_cc_get_principal(
+      context: Context,
+      ccache: CCache,
+      principal: *mut Principal,
+    ) -> ErrorCode;
+    fn krb5_unparse_name(
+      context: Context,
+      principal: Principal,
+      name: *mut *mut c_char,
+    ) -> ErrorCode;
+    fn krb5_free_unparsed_name(context: Context, name: *mut c_char);
+    fn krb5_get_init_creds_opt_alloc(context: Context, opt: *mut InitCredsOpt) -> ErrorCode;
+    fn krb5_get_init_creds_opt_free(context: Context, opt: InitCredsOpt);
+    fn krb5_get_init_creds_opt_set_out_ccache(
+      context: Context,
+      opt: InitCredsOpt,
+      ccache: CCache,
+    ) -> ErrorCode;
+    fn krb5_kt_resolve(context: Context, name: *const c_char, keytab: *mut Keytab) -> ErrorCode;
+    fn krb5_kt_close(context: Context, keytab: Keytab) -> ErrorCode;
+    fn krb5_get_error_message(context: Context, code: ErrorCode) -> *const c_char;
+    fn krb5_free_error_message(context: Context, message: *const c_char);
+  }
+
+  // From krb5_shim.c
+  extern "C" {
+    static clink_krb5_kdc_err_c_principal_unknown: ErrorCode;
+    static clink_krb5_kdc_err_key_exp: ErrorCode;
+    static clink_krb5_kdc_err_preauth_failed: ErrorCode;
+    static clink_krb5_ap_err_bad_integrity: ErrorCode;
+    static clink_krb5_ap_err_skew: ErrorCode;
+    static clink_krb5_realm_unknown: ErrorCode;
+    static clink_krb5_kdc_unreach: ErrorCode;
+    static clink_krb5_realm_cant_resolve: ErrorCode;
+    fn clink_krb5_get_init_creds(
+      context: Context,
+      client: Principal,
+      password: *const c_char,
+      keytab: Keytab,
+      options: InitCredsOpt,
+    ) -> ErrorCode;
+    fn clink_krb5_tgt_endtime(
+      context: Context,
+      ccache: CCache,
+      endtime: *mut i32,
+      found: *mut c_int,
+    ) -> ErrorCode;
+  }
+
+  /// The library couldn't start (no config, usually), so try `kinit`
+  pub struct Unavailable;
+
+  /// What proves we're who we say we are
+  pub enum Key<'a> {
+    Password(&'a str),
+    Keytab(&'a Path),
+  }
+
+  /// Frees everything it holds when dropped, so every early return cleans up
+  struct Session {
+    context: Context,
+    principal: Principal,
+    ccache: CCache,
+    keytab: Keytab,
+    opt: InitCredsOpt,
+  }
+
+  impl Session {
+    fn new() -> Result<Session, Unavailable> {
+      let mut context = ptr::null_mut();
+      if unsafe { krb5_init_context(&mut context) } != 0 || context.is_null() {
+        return Err(Unavailable);
+      }
+      Ok(Session {
+        context,
+        principal: ptr::null_mut(),
+        ccache: ptr::null_mut(),
+        keytab: ptr::null_mut(),
+        opt: ptr::null_mut(),
+      })
+    }
+
+    /// `user@REALM` for a principal
+    fn unparse(&self, principal: Principal) -> Option<String> {
+      unsafe {
+        let mut name = ptr::null_mut();
+        if krb5_unparse_name(self.context, principal, &mut name) != 0 || name.is_null() {
+          return None;
+        }
+        let text = CStr::from_ptr(name).to_string_lossy().into_owned();
+        krb5_free_unparsed_name(self.context, name);
+        Some(text)
+      }
+    }
+
+    /// Opens the default credential cache, closed again on drop
+    fn open_ccache(&mut self) -> Result<(), LoginError> {
+      let code = unsafe { krb5_cc_default(self.context, &mut self.ccache) };
+      self.check(code)
+    }
+
+    fn error(&self, code: ErrorCode) -> String {
+      unsafe {
+        let message = krb5_get_error_message(self.context, code);
+        if message.is_null() {
+          return format!("Kerberos error {}", code);
+        }
+        let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+        krb5_free_error_message(self.context, message);
+        text
+      }
+    }
+
+    fn check(&self, code: ErrorCode) -> Result<(), LoginError> {
+      match code {
+        0 => Ok(()),
+        code => Err(LoginError::Other(self.error(code))),
+      }
+    }
+  }
+
+  impl Drop for Session {
+    fn drop(&mut self) {
+      unsafe {
+        if !self.opt.is_null() {
+          krb5_get_init_creds_opt_free(self.context, self.opt);
+        }
+        if !self.ccache.is_null() {
+          krb5_cc_close(self.context, self.ccache);
+        }
+        if !self.keytab.is_null() {
+          krb5_kt_close(self.context, self.keytab);
+        }
+        if !self.principal.is_null() {
+          krb5_free_principal(self.context, self.principal);
+        }
+        krb5_free_context(self.context);
+      }
+    }
+  }
+
+  pub fn login(principal: &str, key: Key) -> Result<Result<(), LoginError>, Unavailable> {
+    let mut session = Session::new()?;
+    Ok(get_ticket(&mut session, principal, key))
+  }
+
+  pub fn ccache_name() -> Result<Option<String>, Unavailable> {
+    let session = Session::new()?;
+    unsafe {
+      let name = krb5_cc_default_name(session.context);
+      Ok(match name.is_null() {
+        true => None,
+        false => Some(CStr::from_ptr(name).to_string_lossy().into_owned()),
+      })
+    }
+  }
+
+  pub fn destroy() -> Result<Result<(), LoginError>, Unavailable> {
+    let mut session = Session::new()?;
+    if let Err(err) = session.open_ccache() {
+      return Ok(Err(err));
+    }
+    // Destroying closes it too
+    let ccache = std::mem::replace(&mut session.ccache, ptr::null_mut());
+    let code = unsafe { krb5_cc_destroy(session.context, ccache) };
+    Ok(session.check(code))
+  }
+
+  pub fn ticket() -> Result<Option<Ticket>, Unavailable> {
+    let mut session = Session::new()?;
+    if session.open_ccache().is_err() {
+      return Ok(None);
+    }
+    unsafe {
+      if krb5_cc_get_principal(session.context, session.ccache, &mut session.principal) != 0 {
+        return Ok(None);
+      }
+      let principal = match session.unparse(session.principal) {
+        Some(principal) => principal,
+        None => return Ok(None),
+      };
+      let (mut endtime, mut found) = (0, 0);
+      let code = clink_krb5_tgt_endtime(session.context, session.ccache, &mut endtime, &mut found);
+      Ok(Some(Ticket {
+        principal,
+        // Timestamps are unsigned 32 bit, even though the type isn't
+        expires_at: (code == 0 && found != 0).then_some(endtime as u32 as u64),
+      }))
+    }
+  }
+
+  fn get_ticket(session: &mut Session, principal: &str, key: Key) -> Result<(), LoginError> {
+    let nul = |_| LoginError::Other("Usernames, passwords and paths can't contain NUL".to_string());
+    let name = CString::new(principal).map_err(nul)?;
+    let secret = match &key {
+      Key::Password(password) => CString::new(*password).map_err(nul)?,
+      Key::Keytab(path) => CString::new(path.as_os_str().as_bytes()).map_err(nul)?,
+    };
+    unsafe {
+      let code = krb5_parse_name(session.context, name.as_ptr(), &mut session.principal);
+      session.check(code)?;
+      session.open_ccache()?;
+      let code = krb5_get_init_creds_opt_alloc(session.context, &mut session.opt);
+      session.check(code)?;
+      let code =
+        krb5_get_init_creds_opt_set_out_ccache(session.context, session.opt, session.ccache);
+      session.check(code)?;
+      let password = match key {
+        Key::Password(_) => secret.as_ptr(),
+        Key::Keytab(_) => {
+          let code = krb5_kt_resolve(session.context, secret.as_ptr(), &mut session.keytab);
+          session.check(code)?;
+          ptr::null()
+        }
+      };
+      let code = clink_krb5_get_init_creds(
+        session.context,
+        session.principal,
+        password,
+        session.keytab,
+        session.opt,
+      );
+      if code != 0 {
+        return Err(classify(principal, code, session.error(code)));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn classify(principal: &str, code: ErrorCode, message: String) -> LoginError {
+    let is = |codes: &[ErrorCode]| codes.contains(&code);
+    unsafe {
+      if is(&[
+        clink_krb5_kdc_err_preauth_failed,
+        clink_krb5_ap_err_bad_integrity,
+      ]) {
+        LoginError::WrongPassword
+      } else if is(&[clink_krb5_kdc_err_c_principal_unknown]) {
+        LoginError::UnknownPrincipal(principal.to_string())
+      } else if is(&[clink_krb5_kdc_err_key_exp]) {
+        LoginError::PasswordExpired
+      } else if is(&[
+        clink_krb5_realm_unknown,
+        clink_krb5_kdc_unreach,
+        clink_krb5_realm_cant_resolve,
+      ]) {
+        LoginError::KdcUnreachable(realm(principal).to_string())
+      } else if is(&[clink_krb5_ap_err_skew]) {
+        LoginError::ClockSkew
+      } else {
+        LoginError::Other(message)
+      }
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_krb5_errors() {
+      let principal = "user@CSH.RIT.EDU";
+      let classify = |code| classify(principal, code, "message".to_string());
+      unsafe {
+        assert_eq!(
+          classify(clink_krb5_kdc_err_preauth_failed),
+          LoginError::WrongPassword
+        );
+        assert_eq!(
+          classify(clink_krb5_ap_err_bad_integrity),
+          LoginError::WrongPassword
+        );
+        assert_eq!(
+          classify(clink_krb5_kdc_err_c_principal_unknown),
+          LoginError::UnknownPrincipal(principal.to_string())
+        );
+        assert_eq!(
+          classify(clink_krb5_kdc_err_key_exp),
+          LoginError::PasswordExpired
+        );
+        assert_eq!(
+          classify(clink_krb5_kdc_unreach),
+          LoginError::KdcUnreachable("CSH.RIT.EDU".to_string())
+        );
+        assert_eq!(classify(clink_krb5_ap_err_skew), LoginError::ClockSkew);
+      }
+      assert_eq!(classify(0), LoginError::Other("message".to_string()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn classifies_kinit_errors() {
+    let principal = "user@CSH.RIT.EDU";
+    let classify = |stderr| classify_kinit(principal, stderr);
+    assert_eq!(
+      classify("kinit: Password incorrect while getting initial credentials"),
+      LoginError::WrongPassword
+    );
+    assert_eq!(
+      classify(
+        "kinit: Client 'user@CSH.RIT.EDU' not found in Kerberos database while getting initial credentials"
+      ),
+      LoginError::UnknownPrincipal(principal.to_string())
+    );
+    assert_eq!(
+      classify(
+        "kinit: Cannot contact any KDC for realm 'CSH.RIT.EDU' while getting initial credentials"
+      ),
+      LoginError::KdcUnreachable("CSH.RIT.EDU".to_string())
+    );
+    assert_eq!(
+      classify("kinit: Clock skew too great while getting initial credentials"),
+      LoginError::ClockSkew
+    );
+    assert_eq!(
+      classify("kinit: something new"),
+      LoginError::Other("kinit: something new".to_string())
+    );
+  }
+}
@@ -0,0 +1,71 @@
+/*
+ * The parts of talking to libkrb5 that depend on its headers: struct layouts
+ * and error codes. Built against whatever krb5.h is installed (see build.rs),
+ * so src/kerberos.rs only ever handles opaque pointers.
+ */
+
+#include <krb5.h>
+#include <string.h>
+
+const krb5_error_code clink_krb5_kdc_err_c_principal_unknown = KRB5KDC_ERR_C_PRINCIPAL_UNKNOWN;
+const krb5_error_code clink_krb5_kdc_err_key_exp = KRB5KDC_ERR_KEY_EXP;
+const krb5_error_code clink_krb5_kdc_err_preauth_failed = KRB5KDC_ERR_PREAUTH_FAILED;
+const krb5_error_code clink_krb5_ap_err_bad_integrity = KRB5KRB_AP_ERR_BAD_INTEGRITY;
+const krb5_error_code clink_krb5_ap_err_skew = KRB5KRB_AP_ERR_SKEW;
+const krb5_error_code clink_krb5_realm_unknown = KRB5_REALM_UNKNOWN;
+const krb5_error_code clink_krb5_kdc_unreach = KRB5_KDC_UNREACH;
+const krb5_error_code clink_krb5_realm_cant_resolve = KRB5_REALM_CANT_RESOLVE;
+
+/*
+ * Gets initial credentials with a password, or with a keytab if there's no
+ * password. The ticket goes wherever `options` says, we don't keep a copy.
+ */
+krb5_error_code clink_krb5_get_init_creds(krb5_context context, krb5_principal client,
+                                          const char *password, krb5_keytab keytab,
+                                          krb5_get_init_creds_opt *options) {
+  krb5_creds creds;
+  krb5_error_code code;
+
+  memset(&creds, 0, sizeof(creds));
+  if (password != NULL) {
+    code = krb5_get_init_creds_password(context, &creds, client, password, NULL, NULL, 0, NULL,
+                                        options);
+  } else {
+    code = krb5_get_init_creds_keytab(context, &creds, client, keytab, 0, NULL, options);
+  }
+  if (code == 0) {
+    krb5_free_cred_contents(context, &creds);
+  }
+  return code;
+}
+
+/*
+ * Finds when the ticket-granting ticket in `ccache` expires. Returns 0 and
+ * sets `found` to 0 if there isn't one.
+ */
+krb5_error_code clink_krb5_tgt_endtime(krb5_context context, krb5_ccache ccache,
+                                       krb5_timestamp *endtime, int *found) {
+  krb5_cc_cursor cursor;
+  krb5_creds creds;
+  krb5_error_code code;
+  char *server;
+
+  *found = 0;
+  code = krb5_cc_start_seq_get(context, ccache, &cursor);
+  if (code != 0) {
+    return code;
+  }
+  memset(&creds, 0, sizeof(creds));
+  while (!*found && krb5_cc_next_cred(context, ccache, &cursor, &creds) == 0) {
+    if (krb5_unparse_name(context, creds.server, &server) == 0) {
+      if (strncmp(server, "krbtgt/", 7) == 0) {
+        *endtime = creds.times.endtime;
+        *found = 1;
+      }
+      krb5_free_unparsed_name(context, server);
+    }
+    krb5_free_cred_contents(context, &creds);
+  }
+  krb5_cc_end_seq_get(context, ccache, &cursor);
+  return 0;
+}
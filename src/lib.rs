@@ -0,0 +1,29 @@
+//! Client for the Computer Science House drink machines, the same one the
+//! `clink` CLI uses.
+//!
+//! ```no_run
+//! use clink::{DrinkClient, StaticToken};
+//! use std::time::Duration;
+//!
+//! let client = DrinkClient::builder()
+//!   .auth(StaticToken::new(std::env::var("DRINK_TOKEN").unwrap()))
+//!   .timeout(Duration::from_secs(10))
+//!   .build()?;
+//! for machine in client.get_status_for_machine(None)?.machines {
+//!   println!("{}: {} slots", machine.display_name, machine.slots.len());
+//! }
+//! println!("{} credits left", client.get_credits()?);
+//! # Ok::<(), clink::APIError>(())
+//! ```
+
+pub mod api;
+pub mod backend;
+pub mod kerberos;
+pub mod token_cache;
+
+pub use api::{
+  APIError, AuthProvider, DeviceCode, DrinkClient, DrinkClientBuilder, DrinkList, DropPlan, Item,
+  Logout, Machine, PasswordSource, RefreshToken, RetryPolicy, Slot, Spnego, SsoConfig, StaticToken,
+  User, DEFAULT_API_URL,
+};
+pub use backend::{DrinkBackend, MemoryBackend};
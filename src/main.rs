@@ -1,8 +1,14 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use std::io;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
+
+use clink::api;
 
-pub mod api;
 pub mod commands;
+pub mod config;
+pub mod output;
 
 mod ui;
 
@@ -11,58 +17,465 @@ mod ui;
 struct Cli {
   #[clap(subcommand)]
   command: Option<Subcommands>,
-  /// API base URL to use
-  #[clap(value_parser, default_value = "https://drink.csh.rit.edu", long)]
-  api: String,
+  /// API base URL to use [default: https://drink.csh.rit.edu]
+  #[clap(value_parser, long, global = true, env = "CLINK_API")]
+  api: Option<String>,
+  /// Use the settings from `[profile.<PROFILE>]` in your config
+  #[clap(long, global = true, env = "CLINK_PROFILE")]
+  profile: Option<String>,
+  /// Who to get a Kerberos ticket for [default: you]
+  #[clap(long, global = true, env = "CLINK_USERNAME")]
+  username: Option<String>,
+  /// How to print results [default: text]
+  #[clap(value_enum, long, short, global = true, env = "CLINK_OUTPUT")]
+  output: Option<OutputFormat>,
+  /// Show everything we know about errors (status, server response, causes)
+  #[clap(long, short, global = true)]
+  verbose: bool,
+  /// How to get tokens [default: token if CLINK_TOKEN is set, spnego
+  /// otherwise]
+  #[clap(value_enum, long, global = true, env = "CLINK_AUTH")]
+  auth: Option<AuthMethod>,
+  #[clap(flatten)]
+  sso: SsoArgs,
+  #[clap(flatten)]
+  http: HttpArgs,
+  #[clap(flatten)]
+  password: PasswordArgs,
+}
+
+#[derive(clap::Args)]
+struct HttpArgs {
+  /// Give up on requests that take longer than this [default: 30]
+  #[clap(long, global = true, value_name = "SECS", env = "CLINK_TIMEOUT")]
+  timeout: Option<u64>,
+  /// HTTP proxy to go through [default: $https_proxy]
+  #[clap(long, global = true, value_name = "URL", env = "CLINK_PROXY")]
+  proxy: Option<Uri>,
+  /// Trust the certificate authorities in this PEM file instead of the
+  /// system's
+  #[clap(long, global = true, value_name = "PATH", env = "CLINK_CA_BUNDLE")]
+  ca_bundle: Option<PathBuf>,
+  /// Times to retry loading things when the network flakes out (drops are
+  /// never retried) [default: 3]
+  #[clap(long, global = true, value_name = "N", env = "CLINK_RETRIES")]
+  retries: Option<u32>,
+}
+
+/// Where the Kerberos password comes from when clink has to log in. Without
+/// any of these, clink asks on the terminal.
+#[derive(clap::Args)]
+struct PasswordArgs {
+  /// Read your Kerberos password from stdin
+  #[clap(long, global = true, conflicts_with_all = ["password_file", "keytab"])]
+  password_stdin: bool,
+  /// Read your Kerberos password from a file
+  #[clap(long, global = true, value_name = "PATH", conflicts_with = "keytab")]
+  password_file: Option<PathBuf>,
+  /// Run this to get your Kerberos password, like `pass show csh`
+  #[clap(
+    long,
+    global = true,
+    value_name = "COMMAND",
+    env = "CLINK_PASSWORD_COMMAND"
+  )]
+  password_command: Option<String>,
+  /// Log in with a keytab instead of a password
+  #[clap(long, global = true, value_name = "PATH")]
+  keytab: Option<PathBuf>,
+}
+
+impl PasswordArgs {
+  /// Flags win over `CLINK_PASSWORD_COMMAND`, so one run can override it
+  fn source(self) -> PasswordSource {
+    if let Some(keytab) = self.keytab {
+      PasswordSource::Keytab(keytab)
+    } else if self.password_stdin {
+      PasswordSource::Stdin
+    } else if let Some(path) = self.password_file {
+      PasswordSource::File(path)
+    } else if let Some(command) = self.password_command {
+      PasswordSource::Command(command)
+    } else {
+      PasswordSource::Prompt
+    }
+  }
+}
+
+#[derive(clap::Args)]
+struct SsoArgs {
+  /// Base URL of the Keycloak server [default: https://sso.csh.rit.edu/auth]
+  #[clap(value_parser, long, global = true, env = "CLINK_SSO_URL")]
+  sso_url: Option<String>,
+  /// Keycloak realm to get tokens from [default: csh]
+  #[clap(value_parser, long, global = true, env = "CLINK_SSO_REALM")]
+  sso_realm: Option<String>,
+  /// OpenID Connect client ID [default: clidrink]
+  #[clap(value_parser, long, global = true, env = "CLINK_CLIENT_ID")]
+  client_id: Option<String>,
+  /// Kerberos realm to log in to [default: CSH.RIT.EDU]
+  #[clap(value_parser, long, global = true, env = "CLINK_KERBEROS_REALM")]
+  kerberos_realm: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Subcommands {
   /// Drops a drink
+  ///
+  /// Either give a machine and slot number (`clink drop bigdrink 3`), or the
+  /// name of the drink you want (`clink drop coke`) and clink will find it.
   Drop {
-    /// Machine to drop from
-    #[clap(value_parser)]
-    machine: String,
-    /// Slot to drop from
-    #[clap(value_parser)]
-    slot: u8,
+    /// Drink to drop, or a machine followed by a slot number
+    #[clap(value_parser, num_args = 1..=2, required = true, value_names = ["MACHINE", "SLOT|ITEM"])]
+    target: Vec<String>,
+    /// Only look for the drink in this machine
+    #[clap(value_parser, long, short)]
+    machine: Option<String>,
+    /// Drop even if the machine is offline, the slot looks empty, or you
+    /// don't seem to have enough credits
+    #[clap(long)]
+    force: bool,
+    /// Don't ask before dropping
+    #[clap(long, short)]
+    yes: bool,
+    /// Check everything and show what would be dropped, without dropping it
+    #[clap(long)]
+    dry_run: bool,
   },
   /// Lists available drinks
   List {
-    /// Machine whose contents should be shown (if not specified, all will be shown)
+    /// Machine whose contents should be shown (if not specified, your
+    /// `default_machine` or all machines will be shown)
     #[clap(value_parser)]
     machine: Option<String>,
+    /// Show every machine, even if you have a `default_machine` set
+    #[clap(long, conflicts_with = "machine")]
+    all: bool,
   },
   /// Prints the number of credits in your account
   Credits,
   /// Generates an API token (Plumbing)
   Token,
+  /// Gets a fresh token, asking for your password if your Kerberos ticket is
+  /// gone
+  Login {
+    /// Ask for your password and get a new Kerberos ticket, even if the one
+    /// you have still works
+    #[clap(long)]
+    password: bool,
+    /// Log in from a browser with a code instead, for when there's no
+    /// Kerberos (like off the CSH network)
+    #[clap(long, conflicts_with = "password")]
+    device: bool,
+  },
+  /// Forgets your cached token
+  Logout {
+    /// Also destroy your Kerberos ticket, if clink is what got it
+    #[clap(long)]
+    kdestroy: bool,
+  },
+  /// Shows who you're logged in as, and when your credentials expire
+  Whoami,
+  /// Checks your Kerberos ticket, clock and network, for when logging in
+  /// doesn't work
+  Doctor,
+  /// Inspects or edits your config file
+  Config {
+    #[clap(subcommand)]
+    action: ConfigAction,
+  },
+  /// Lists profiles, or picks the one to use by default
+  Profile {
+    #[clap(subcommand)]
+    action: ProfileAction,
+  },
 }
 
-use crate::api::APIError;
+#[derive(Subcommand)]
+enum ProfileAction {
+  /// Prints every profile, with a `*` next to the one in use
+  List,
+  /// Uses a profile from now on, without needing `--profile`
+  Use {
+    #[clap(value_parser, required_unless_present = "none")]
+    name: Option<String>,
+    /// Go back to not using a profile
+    #[clap(long, conflicts_with = "name")]
+    none: bool,
+  },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+  /// Prints the value of an option (like `sso.realm`)
+  Get {
+    #[clap(value_parser)]
+    key: String,
+    /// Print tokens instead of <redacted> when KEY is a table
+    #[clap(long)]
+    show_secrets: bool,
+  },
+  /// Sets an option in your config file
+  Set {
+    #[clap(value_parser)]
+    key: String,
+    #[clap(value_parser)]
+    value: String,
+  },
+  /// Prints every option from the defaults and config files
+  List {
+    /// Print tokens instead of <redacted>
+    #[clap(long)]
+    show_secrets: bool,
+  },
+  /// Prints where your config file lives
+  Path {
+    /// Print the path to the system-wide config file instead
+    #[clap(long)]
+    system: bool,
+  },
+}
+
+use crate::config::AuthMethod;
+use crate::output::OutputFormat;
 use crate::Subcommands::*;
+use clink::api::{
+  DrinkClientBuilder, PasswordSource, RefreshToken, RetryPolicy, Spnego, SsoConfig, StaticToken,
+};
+use clink::kerberos;
+use clink::token_cache::TokenCache;
+use http::Uri;
 
 fn main() -> ExitCode {
   let cli = Cli::parse();
-  let result = process_command(cli);
+  // These only touch the config file, so they work even when it's broken
+  match cli.command {
+    Some(Config { action }) => return config_exit_code(process_config_command(action)),
+    Some(Profile { action }) => {
+      return config_exit_code(process_profile_command(action, cli.profile.as_deref()))
+    }
+    _ => {}
+  }
+  let config = match config::Config::load(cli.profile.as_deref()) {
+    Ok(config) => config,
+    Err(err) => {
+      eprintln!("Error: {}", err);
+      return ExitCode::FAILURE;
+    }
+  };
+  let verbose = cli.verbose;
+  let result = process_command(cli, config);
+  match result {
+    Ok(code) => code,
+    Err(err) if verbose => {
+      eprintln!("Error: {}", err.verbose());
+      err.exit_code().into()
+    }
+    Err(err) => {
+      eprintln!("Error: {}", err);
+      err.exit_code().into()
+    }
+  }
+}
+
+/// Flags and environment variables win over the config file, which wins over
+/// the defaults
+fn sso_config(args: SsoArgs, config: config::SsoSection) -> SsoConfig {
+  let defaults = SsoConfig::default();
+  SsoConfig {
+    url: args.sso_url.or(config.url).unwrap_or(defaults.url),
+    realm: args.sso_realm.or(config.realm).unwrap_or(defaults.realm),
+    client_id: args
+      .client_id
+      .or(config.client_id)
+      .unwrap_or(defaults.client_id),
+    kerberos_realm: args
+      .kerberos_realm
+      .or(config.kerberos_realm)
+      .unwrap_or(defaults.kerberos_realm),
+  }
+}
+
+/// Tokens only come from the environment or the config file, never flags,
+/// so they don't end up in `ps` or shell history
+fn auth(
+  builder: DrinkClientBuilder,
+  method: Option<AuthMethod>,
+  config: config::AuthSection,
+) -> DrinkClientBuilder {
+  let env = |name| {
+    std::env::var(name)
+      .ok()
+      .filter(|value: &String| !value.is_empty())
+  };
+  let token = env("CLINK_TOKEN");
+  let method = method.or(config.method).unwrap_or(match token {
+    Some(_) => AuthMethod::Token,
+    None => AuthMethod::Spnego,
+  });
+  let missing = |message: &str| {
+    Cli::command()
+      .error(clap::error::ErrorKind::MissingRequiredArgument, message)
+      .exit()
+  };
+  match method {
+    AuthMethod::Spnego => builder.auth(Spnego),
+    AuthMethod::Token => match token.or(config.token) {
+      Some(token) => builder.auth(StaticToken::new(token)),
+      None => missing("--auth token needs CLINK_TOKEN or auth.token in your config"),
+    },
+    AuthMethod::RefreshToken => match env("CLINK_REFRESH_TOKEN").or(config.refresh_token) {
+      Some(token) => builder.auth(RefreshToken::new(token)),
+      None => missing(
+        "--auth refresh-token needs CLINK_REFRESH_TOKEN or auth.refresh_token in your config",
+      ),
+    },
+  }
+}
+
+fn http_config(
+  mut builder: DrinkClientBuilder,
+  args: HttpArgs,
+  config: config::HttpSection,
+) -> DrinkClientBuilder {
+  if let Some(timeout) = args.timeout.or(config.timeout) {
+    builder = builder.timeout(Duration::from_secs(timeout));
+  }
+  if let Some(timeout) = config.connect_timeout {
+    builder = builder.connect_timeout(Duration::from_secs(timeout));
+  }
+  let proxy = args.proxy.or(config.proxy);
+  if let Some(proxy) = proxy {
+    builder = builder.proxy(proxy);
+  }
+  if let Some(path) = args.ca_bundle.or(config.ca_bundle) {
+    builder = builder.ca_bundle(path);
+  }
+  let mut retry = RetryPolicy::default();
+  if let Some(retries) = args.retries.or(config.retries) {
+    retry.max_retries = retries;
+  }
+  if let Some(delay) = config.max_retry_delay_ms {
+    retry.max_delay = Duration::from_millis(delay);
+  }
+  builder.retry(retry)
+}
+
+fn config_exit_code(result: Result<(), config::ConfigError>) -> ExitCode {
   match result {
-    Ok(_) => 0,
-    Err(APIError::LoginAborted) => 0,
+    Ok(_) => ExitCode::SUCCESS,
     Err(err) => {
       eprintln!("Error: {}", err);
-      1
+      ExitCode::FAILURE
     }
   }
-  .into()
 }
 
-fn process_command(cli: Cli) -> Result<(), api::APIError> {
-  let mut api = api::API::new(cli.api, Box::new(api::API::default_password_prompt));
+fn process_profile_command(
+  action: ProfileAction,
+  profile: Option<&str>,
+) -> Result<(), config::ConfigError> {
+  match action {
+    ProfileAction::List => commands::profile::list(profile),
+    ProfileAction::Use { name, .. } => commands::profile::use_profile(name.as_deref()),
+  }
+}
+
+fn process_config_command(action: ConfigAction) -> Result<(), config::ConfigError> {
+  match action {
+    ConfigAction::Get { key, show_secrets } => commands::config::get(&key, show_secrets),
+    ConfigAction::Set { key, value } => commands::config::set(&key, &value),
+    ConfigAction::List { show_secrets } => commands::config::list(show_secrets),
+    ConfigAction::Path { system } => commands::config::path(system),
+  }
+}
+
+fn process_command(cli: Cli, config: config::Config) -> Result<ExitCode, api::APIError> {
+  let api_base_url = cli
+    .api
+    .or(config.api.clone())
+    .unwrap_or_else(|| api::DEFAULT_API_URL.to_string());
+  let token_cache = match &config.active_profile {
+    Some(profile) => {
+      // A profile for someone else gets its own Kerberos tickets too, or
+      // SPNEGO would happily use yours. Unless you've picked some yourself.
+      if config.switches_user && std::env::var_os("KRB5CCNAME").is_none() {
+        if let Some(dir) = TokenCache::profile_dir(profile) {
+          if let Err(err) = kerberos::use_ccache(&dir.join("krb5cc")) {
+            eprintln!("Warning: Couldn't set up {}: {}", dir.display(), err);
+          }
+        }
+      }
+      TokenCache::new(TokenCache::profile_path(profile))
+    }
+    None => TokenCache::default(),
+  };
+  let mut api = api::DrinkClient::builder()
+    .base_url(api_base_url)
+    .sso(sso_config(cli.sso, config.sso.clone()))
+    .token_cache(token_cache)
+    .password_source(cli.password.source());
+  if let Some(username) = cli.username.or(config.username.clone()) {
+    api = api.username(username);
+  }
+  api = http_config(api, cli.http, config.http.clone());
+  let api = auth(api, cli.auth, config.auth.clone()).build()?;
+  let format = cli.output.or(config.output).unwrap_or_default();
+  let stdout = &mut io::stdout().lock();
   match cli.command {
-    Some(Drop { machine, slot }) => commands::drop::drop(&mut api, machine, slot),
-    Some(List { machine }) => commands::list::list(&mut api, machine),
-    Some(Credits) => commands::credits::credits(&mut api),
-    Some(Token) => commands::token::token(&mut api),
-    None => ui::ui_common::launch(api),
+    Some(Drop {
+      target,
+      machine,
+      force,
+      yes,
+      dry_run,
+    }) => {
+      if target.len() == 2 && machine.is_some() {
+        Cli::command()
+          .error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "Give the machine either as an argument or with --machine, not both",
+          )
+          .exit();
+      }
+      let target = commands::drop::DropTarget::from_args(target, machine);
+      let options = commands::drop::DropOptions {
+        force,
+        confirm: !yes && config.confirm_drops.unwrap_or(true),
+        dry_run,
+      };
+      commands::drop::drop(
+        &api,
+        stdout,
+        target,
+        config.default_machine,
+        options,
+        format,
+      )
+    }
+    Some(List { machine, all }) => {
+      let machine = match all {
+        true => None,
+        false => machine.or(config.default_machine),
+      };
+      commands::list::list(&api, stdout, machine, format)
+    }
+    Some(Credits) => commands::credits::credits(&api, stdout, format),
+    Some(Token) => commands::token::token(&api, stdout, format),
+    Some(Login { password, device }) => {
+      commands::login::login(&api, stdout, password, device, format)
+    }
+    Some(Logout { kdestroy }) => commands::logout::logout(&api, stdout, kdestroy, format),
+    Some(Whoami) => commands::whoami::whoami(&api, stdout, format),
+    Some(Doctor) => {
+      return Ok(match commands::doctor::doctor(&api, stdout, format) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+      })
+    }
+    // Handled before we load the config
+    Some(Config { .. } | Profile { .. }) => unreachable!(),
+    None => ui::ui_common::launch(cursive::default(), api, &config),
   }
+  .map(|_| ExitCode::SUCCESS)
 }
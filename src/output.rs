@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+/// Bumped whenever machine-readable output changes in a way that could break
+/// something parsing it. Adding fields doesn't count, removing or renaming
+/// them does.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+  /// For humans
+  #[default]
+  Text,
+  /// One JSON document
+  Json,
+  /// One JSON document per record, one per line
+  Ndjson,
+  /// Comma separated, with a header row
+  Csv,
+  /// Tab separated, with a header row
+  Tsv,
+}
+
+/// Wraps everything we print as JSON, so consumers can tell what they're
+/// looking at and which version of it they got
+#[derive(Serialize)]
+struct Envelope<T: Serialize> {
+  schema_version: u32,
+  command: &'static str,
+  data: T,
+}
+
+/// The result of a command, in a shape every output format can deal with
+pub trait Output: Serialize {
+  /// Flat version of the output, for CSV/TSV rows and NDJSON lines. Must be a
+  /// struct of plain values (no nesting), or the CSV writer will refuse it.
+  type Record: Serialize;
+
+  /// Name of the command, as it appears in the JSON envelope
+  fn command(&self) -> &'static str;
+  fn text(&self) -> String;
+  fn records(&self) -> Vec<Self::Record>;
+}
+
+/// Writes `output` to `out` (stdout, outside of tests) in `format`
+pub fn print<T: Output>(out: &mut impl Write, format: OutputFormat, output: &T) {
+  let result = match format {
+    OutputFormat::Text => write!(out, "{}", output.text()),
+    OutputFormat::Json => serde_json::to_writer_pretty(&mut *out, &envelope(output, output))
+      .map_err(io::Error::from)
+      .and_then(|_| writeln!(out)),
+    OutputFormat::Ndjson => output.records().iter().try_for_each(|record| {
+      serde_json::to_writer(&mut *out, &envelope(output, record))?;
+      writeln!(out)
+    }),
+    OutputFormat::Csv => write_delimited(out, b',', output),
+    OutputFormat::Tsv => write_delimited(out, b'\t', output),
+  };
+  match result {
+    // Whoever was reading (probably `head`) has what they wanted
+    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+    // Same as println!, if we can't write to stdout there's nobody left to tell
+    result => result.expect("failed printing to stdout"),
+  }
+}
+
+fn envelope<T: Output, D: Serialize>(output: &T, data: D) -> Envelope<D> {
+  Envelope {
+    schema_version: SCHEMA_VERSION,
+    command: output.command(),
+    data,
+  }
+}
+
+fn write_delimited<T: Output>(
+  writer: &mut impl Write,
+  delimiter: u8,
+  output: &T,
+) -> io::Result<()> {
+  let mut writer = csv::WriterBuilder::new()
+    .delimiter(delimiter)
+    .from_writer(writer);
+  for record in output.records() {
+    writer.serialize(record)?;
+  }
+  writer.flush()
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How close to expiry a token can get before we go and fetch a new one
+pub const TOKEN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Token {
+  /// Value for the `Authorization` header (including the `Bearer ` prefix)
+  pub value: String,
+  /// Seconds since the unix epoch, if SSO told us when the token expires
+  pub expires_at: Option<u64>,
+  /// For getting a new token from SSO without logging in again, if we have
+  /// one (like after `clink login --device`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
+}
+
+impl Token {
+  pub fn new(value: String, expires_in: Option<u64>) -> Token {
+    Token {
+      value,
+      expires_at: expires_in.map(|expires_in| now() + expires_in),
+      refresh_token: None,
+    }
+  }
+
+  pub fn with_refresh_token(mut self, refresh_token: Option<String>) -> Token {
+    self.refresh_token = refresh_token;
+    self
+  }
+
+  /// True if the token is expired, or will be within `grace`
+  pub fn expires_within(&self, grace: Duration) -> bool {
+    match self.expires_at {
+      Some(expires_at) => now() + grace.as_secs() >= expires_at,
+      None => false,
+    }
+  }
+
+  pub fn is_fresh(&self) -> bool {
+    !self.expires_within(TOKEN_GRACE_PERIOD)
+  }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+/// Keeps the bearer token around between invocations, so scripted use of clink
+/// doesn't have to do the whole SPNEGO dance every time.
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+  path: Option<PathBuf>,
+}
+
+impl Default for TokenCache {
+  fn default() -> Self {
+    TokenCache::new(TokenCache::default_path())
+  }
+}
+
+impl TokenCache {
+  /// A cache with no path never stores anything
+  pub fn new(path: Option<PathBuf>) -> TokenCache {
+    TokenCache { path }
+  }
+
+  /// `$XDG_CACHE_HOME/clink/token.json`
+  pub fn default_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("clink").join("token.json"))
+  }
+
+  /// `$XDG_CACHE_HOME/clink/profiles/<profile>/token.json`, so every profile
+  /// logs in on its own. `None` for names that aren't safe in a path.
+  pub fn profile_path(profile: &str) -> Option<PathBuf> {
+    TokenCache::profile_dir(profile).map(|dir| dir.join("token.json"))
+  }
+
+  /// `$XDG_CACHE_HOME/clink/profiles/<profile>`, where everything a profile
+  /// logs in with lives. `None` for names that aren't safe in a path.
+  pub fn profile_dir(profile: &str) -> Option<PathBuf> {
+    if Path::new(profile).file_name() != Some(profile.as_ref()) {
+      return None;
+    }
+    dirs::cache_dir().map(|dir| dir.join("clink").join("profiles").join(profile))
+  }
+
+  pub fn path(&self) -> Option<&PathBuf> {
+    self.path.as_ref()
+  }
+
+  pub fn load(&self) -> Option<Token> {
+    let contents = fs::read_to_string(self.path.as_ref()?).ok()?;
+    serde_json::from_str(&contents).ok()
+  }
+
+  pub fn store(&self, token: &Token) -> io::Result<()> {
+    let path = match &self.path {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    write_private(path, serde_json::to_string(token)?.as_bytes())
+  }
+
+  pub fn clear(&self) -> io::Result<()> {
+    match &self.path {
+      Some(path) => remove(path),
+      None => Ok(()),
+    }
+  }
+
+  /// Where we note down which credential cache clink put a Kerberos ticket
+  /// in, so `logout` only destroys tickets that are ours. Lives next to the
+  /// token.
+  fn ccache_path(&self) -> Option<PathBuf> {
+    self.path.as_ref().map(|path| path.with_file_name("ccache"))
+  }
+
+  pub fn remember_ccache(&self, name: &str) -> io::Result<()> {
+    let path = match self.ccache_path() {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    write_private(&path, name.as_bytes())
+  }
+
+  /// The credential cache clink last logged in to, if it did
+  pub fn remembered_ccache(&self) -> Option<String> {
+    fs::read_to_string(self.ccache_path()?).ok()
+  }
+
+  pub fn forget_ccache(&self) -> io::Result<()> {
+    match self.ccache_path() {
+      Some(path) => remove(&path),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Writes a file only we can read, making its directory if it has to
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(path)?;
+  // mode() only applies to newly created files, so tighten up old ones too
+  file.set_permissions(fs::Permissions::from_mode(0o600))?;
+  file.write_all(contents)
+}
+
+/// Removes a file, if it's there
+fn remove(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+    _ => Ok(()),
+  }
+}
@@ -0,0 +1,64 @@
+use cursive::event::{Event, Key};
+use serde::Deserialize;
+
+/// A single key from the config file: either a character (`"q"`) or the name
+/// of a special key (`"Left"`, `"Enter"`, `"Esc"`, ...)
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct KeyBinding(pub Event);
+
+impl TryFrom<String> for KeyBinding {
+  type Error = String;
+  fn try_from(name: String) -> Result<Self, Self::Error> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+      return Ok(KeyBinding(Event::Char(c)));
+    }
+    let key = match name.to_lowercase().as_str() {
+      "left" => Key::Left,
+      "right" => Key::Right,
+      "up" => Key::Up,
+      "down" => Key::Down,
+      "enter" => Key::Enter,
+      "esc" | "escape" => Key::Esc,
+      "backspace" => Key::Backspace,
+      "tab" => Key::Tab,
+      "del" | "delete" => Key::Del,
+      "home" => Key::Home,
+      "end" => Key::End,
+      "pageup" => Key::PageUp,
+      "pagedown" => Key::PageDown,
+      _ => return Err(format!("unknown key {:?}", name)),
+    };
+    Ok(KeyBinding(Event::Key(key)))
+  }
+}
+
+/// Keys the TUI listens for, after applying the config file
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+  pub quit: Event,
+  pub back: Event,
+  pub select: Event,
+}
+
+impl Default for KeyBindings {
+  fn default() -> Self {
+    KeyBindings {
+      quit: Event::Char('q'),
+      back: Event::Key(Key::Left),
+      select: Event::Key(Key::Right),
+    }
+  }
+}
+
+impl From<&crate::config::KeySection> for KeyBindings {
+  fn from(keys: &crate::config::KeySection) -> Self {
+    let defaults = KeyBindings::default();
+    KeyBindings {
+      quit: keys.quit.clone().map_or(defaults.quit, |key| key.0),
+      back: keys.back.clone().map_or(defaults.back, |key| key.0),
+      select: keys.select.clone().map_or(defaults.select, |key| key.0),
+    }
+  }
+}
@@ -1,2 +1,6 @@
+pub mod keys;
 pub mod store;
 pub mod ui_common;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,290 @@
+//! Drives the TUI headlessly with cursive's puppet backend, pressing keys and
+//! snapshotting what ends up on screen
+
+use crate::config::Config;
+use crate::ui::ui_common::Tui;
+use clink::api::{APIError, DrinkList, PasswordFunction, PasswordResult, User};
+use clink::backend::{DrinkBackend, MemoryBackend};
+use cursive::backends::puppet::observed::ObservedScreen;
+use cursive::backends::puppet::Backend;
+use cursive::event::{Event, Key};
+use cursive::reexports::crossbeam_channel::{Receiver, Sender};
+use cursive::{Cursive, CursiveRunner, Vec2};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait for something to show up before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Harness {
+  runner: CursiveRunner<Cursive>,
+  input: Sender<Option<Event>>,
+  frames: Receiver<ObservedScreen>,
+  screen: String,
+  tui: Option<Tui>,
+}
+
+impl Harness {
+  fn new(api: impl DrinkBackend + 'static) -> Harness {
+    let backend = Backend::init(Some(Vec2::new(80, 24)));
+    let input = backend.input();
+    let frames = backend.stream();
+    let mut siv = Cursive::new();
+    let tui = Tui::new(&mut siv, api, &Config::default());
+    let mut runner = siv.into_runner(backend);
+    runner.refresh();
+    Harness {
+      runner,
+      input,
+      frames,
+      screen: String::new(),
+      tui: Some(tui),
+    }
+  }
+
+  fn press(&mut self, event: impl Into<Event>) {
+    self.input.send(Some(event.into())).unwrap();
+    self.runner.step();
+    self.update_screen();
+  }
+
+  fn type_text(&mut self, text: &str) {
+    for c in text.chars() {
+      self.press(c);
+    }
+  }
+
+  /// Keeps the event loop going until `text` is on screen, then returns the
+  /// whole screen
+  fn wait_for(&mut self, text: &str) -> String {
+    let start = Instant::now();
+    while !self.screen.contains(text) {
+      if start.elapsed() > TIMEOUT {
+        panic!(
+          "{:?} never showed up, the screen is:\n{}",
+          text, self.screen
+        );
+      }
+      self.runner.step();
+      self.update_screen();
+    }
+    self.screen.clone()
+  }
+
+  /// Renders the newest frame as plain text, one line per row
+  fn update_screen(&mut self) {
+    if let Some(frame) = self.frames.try_iter().last() {
+      let size = frame.size();
+      self.screen = (0..size.y)
+        .map(|y| {
+          let line: String = (0..size.x)
+            // Cleared cells come back as continuations, and nothing we draw
+            // is wider than one cell, so those are blanks too
+            .map(|x| match &frame[Vec2::new(x, y)] {
+              Some(cell) => cell
+                .letter
+                .as_option()
+                .cloned()
+                .unwrap_or_else(|| " ".to_string()),
+              None => " ".to_string(),
+            })
+            .collect();
+          line.trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    }
+  }
+}
+
+impl Drop for Harness {
+  fn drop(&mut self) {
+    self.runner.quit();
+    if let Some(tui) = self.tui.take() {
+      tui.finish().ok();
+    }
+  }
+}
+
+/// A [`MemoryBackend`] that makes you log in first, like [`clink::DrinkClient`]
+/// does when there's no Kerberos ticket. The password is "hunter2".
+struct LoginBackend {
+  inner: MemoryBackend,
+  password_function: Arc<Mutex<Option<Box<PasswordFunction>>>>,
+  logged_in: Arc<AtomicBool>,
+  /// Checking a password waits on this, so a test can hold the lock to
+  /// catch the dialog mid-check
+  checking: Arc<Mutex<()>>,
+}
+
+impl LoginBackend {
+  fn new() -> Self {
+    LoginBackend {
+      inner: MemoryBackend::sample(),
+      password_function: Arc::new(Mutex::new(None)),
+      logged_in: Arc::new(AtomicBool::new(false)),
+      checking: Arc::new(Mutex::new(())),
+    }
+  }
+
+  fn login(&self) -> Result<(), APIError> {
+    // Holding the lock means only one thing asks for a password at a time
+    let password_function = self.password_function.lock().unwrap();
+    if self.logged_in.load(Ordering::SeqCst) {
+      return Ok(());
+    }
+    let logged_in = Arc::clone(&self.logged_in);
+    let checking = Arc::clone(&self.checking);
+    (password_function.as_ref().unwrap())(
+      "user".to_string(),
+      Box::new(move |password| {
+        let _checking = checking.lock().unwrap();
+        let success = password == "hunter2";
+        logged_in.store(success, Ordering::SeqCst);
+        Ok(PasswordResult {
+          success,
+          message: match success {
+            true => String::new(),
+            false => "kinit: Password incorrect while getting initial credentials".to_string(),
+          },
+        })
+      }),
+    );
+    match self.logged_in.load(Ordering::SeqCst) {
+      true => Ok(()),
+      false => Err(APIError::LoginAborted),
+    }
+  }
+}
+
+impl DrinkBackend for LoginBackend {
+  fn get_status_for_machine(&self, machine: Option<&str>) -> Result<DrinkList, APIError> {
+    self.login()?;
+    self.inner.get_status_for_machine(machine)
+  }
+
+  fn get_credits(&self) -> Result<i64, APIError> {
+    self.login()?;
+    self.inner.get_credits()
+  }
+
+  fn drop(&self, machine: String, slot: u8) -> Result<i64, APIError> {
+    self.login()?;
+    self.inner.drop(machine, slot)
+  }
+
+  fn get_user_info(&self) -> Result<User, APIError> {
+    self.login()?;
+    self.inner.get_user_info()
+  }
+
+  fn get_token(&self) -> Result<String, APIError> {
+    self.login()?;
+    self.inner.get_token()
+  }
+
+  fn set_password_prompt(&mut self, prompt: Box<PasswordFunction>) {
+    *self.password_function.lock().unwrap() = Some(prompt);
+  }
+}
+
+#[test]
+fn machine_list() {
+  let mut tui = Harness::new(MemoryBackend::sample());
+  tui.wait_for("Credits: 200");
+  insta::assert_snapshot!(tui.wait_for("Little Drink"));
+}
+
+#[test]
+fn item_list() {
+  let mut tui = Harness::new(MemoryBackend::sample());
+  tui.wait_for("Little Drink");
+  tui.wait_for("Credits: 200");
+  tui.press(Key::Enter);
+  insta::assert_snapshot!(tui.wait_for("Dr. Pepper"));
+
+  // Back to the machine list
+  tui.press(Key::Left);
+  assert!(!tui.wait_for("Little Drink").contains("Dr. Pepper"));
+}
+
+#[test]
+fn drop_success() {
+  let backend = MemoryBackend::sample();
+  let mut tui = Harness::new(backend.clone());
+  tui.wait_for("Little Drink");
+  tui.wait_for("Credits: 200");
+  tui.press(Key::Enter);
+  tui.wait_for("Dr. Pepper");
+  tui.press(Key::Down);
+  tui.press(Key::Enter);
+  insta::assert_snapshot!("drop_confirm", tui.wait_for("Drop a drink?"));
+  tui.press(Key::Enter);
+  insta::assert_snapshot!(tui.wait_for("Enjoy!"));
+  assert_eq!(backend.credits(), 150);
+  tui.press(Key::Enter);
+  tui.wait_for("Credits: 150");
+}
+
+#[test]
+fn drop_error() {
+  let backend = MemoryBackend::sample();
+  let mut tui = Harness::new(backend.clone());
+  tui.wait_for("Little Drink");
+  tui.wait_for("Credits: 200");
+  tui.press(Key::Enter);
+  tui.wait_for("Dr. Pepper");
+  tui.press(Key::Down);
+  tui.press(Key::Down);
+  tui.press(Key::Enter);
+  tui.wait_for("Drop a drink?");
+  tui.press(Key::Enter);
+  insta::assert_snapshot!(tui.wait_for("Couldn't drop a drink"));
+  assert_eq!(backend.credits(), 200);
+}
+
+#[test]
+fn password_dialog() {
+  let backend = LoginBackend::new();
+  let checking = Arc::clone(&backend.checking);
+  let mut tui = Harness::new(backend);
+  insta::assert_snapshot!(tui.wait_for("Enter login for user"));
+
+  tui.type_text("hunter3");
+  let check = checking.lock().unwrap();
+  tui.press(Key::Enter);
+  insta::assert_snapshot!("password_checking", tui.wait_for("Authenticating..."));
+  // The UI keeps going while the check runs, but ignores typing
+  tui.type_text("abc");
+  assert!(tui.wait_for("Authenticating...").contains("*******_"));
+  drop(check);
+  insta::assert_snapshot!("password_wrong", tui.wait_for("Failed attempts: 1"));
+
+  tui.type_text("hunter3");
+  tui.press(Key::Enter);
+  tui.wait_for("Failed attempts: 2");
+  tui.type_text("hunter2");
+  tui.press(Key::Enter);
+  tui.wait_for("Credits: 200");
+  assert!(!tui.wait_for("Little Drink").contains("Enter login"));
+}
+
+#[test]
+fn password_cancel() {
+  let mut tui = Harness::new(LoginBackend::new());
+  tui.wait_for("Enter login for user");
+  tui.press(Key::Tab);
+  tui.press(Key::Enter);
+  tui.wait_for("Login cancelled");
+  tui.press(Key::Enter);
+  assert!(!tui.runner.is_running());
+}
+
+#[test]
+fn quit() {
+  let mut tui = Harness::new(MemoryBackend::sample());
+  tui.wait_for("Little Drink");
+  tui.press('q');
+  assert!(!tui.runner.is_running());
+}
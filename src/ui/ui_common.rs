@@ -1,5 +1,8 @@
-use crate::api::{APIError, DrinkList, Machine, Slot, API};
+use crate::config::Config;
+use crate::ui::keys::KeyBindings;
 use crate::ui::store::{ListenerView, Store};
+use clink::api::{APIError, DrinkList, DropPlan, Machine, Slot};
+use clink::backend::DrinkBackend;
 use cursive;
 use cursive::align::{HAlign, VAlign};
 use cursive::event::{Event, EventResult, Key};
@@ -13,151 +16,251 @@ use cursive::views::{
   ShadowView, TextView,
 };
 use cursive::{Cursive, CursiveRunnable};
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
 
-struct ModelData {
+struct ModelData<B: DrinkBackend> {
   credits: Mutex<Store<Option<i64>>>,
   machines: Mutex<Store<Option<DrinkList>>>,
-  api: API,
+  api: B,
+  keys: KeyBindings,
+  confirm_drops: bool,
 }
 
 // This should really get cleaned up:
-type Model = Arc<ModelData>;
+type Model<B> = Arc<ModelData<B>>;
 
-/// Entrypoint, CLI will call this when we start up!
-pub fn launch(mut api: API) -> Result<(), APIError> {
-  let mut siv = cursive::default();
-  let (tx_credential, rx_credential) = channel();
-  let tx_credential = Arc::new(Mutex::new(tx_credential));
-  let tx_credential_clone = Arc::clone(&tx_credential);
-  {
-    let cb_sink = siv.cb_sink().clone();
-    let (tx_prompt, rx_prompt) = channel();
-    let (tx_close, rx_close) = channel();
-    let is_quit = Arc::new(Mutex::new(false));
-    api.set_password_prompt(Box::new(move |username, password_cb| {
-      if *is_quit.lock().unwrap() {
-        return;
-      }
-      tx_prompt.send(username).unwrap();
-      loop {
-        match rx_credential.recv() {
-          Ok(Some(password)) => {
-            let result = (password_cb)(password);
-            let success = result
-              .as_ref()
-              .map(|result| result.success)
-              .unwrap_or(false);
-            tx_close.send(result).unwrap();
-            if success {
-              break;
-            }
-          }
-          Ok(None) | Err(_) => {
-            *is_quit.lock().unwrap() = true;
-            tx_close.send(Err(APIError::LoginAborted)).unwrap();
-            break;
+/// Entrypoint, CLI will call this when we start up! `siv` decides which
+/// terminal backend we draw with, normally `cursive::default()`.
+pub fn launch<B: DrinkBackend + 'static>(
+  mut siv: CursiveRunnable,
+  api: B,
+  config: &Config,
+) -> Result<(), APIError> {
+  let tui = Tui::new(&mut siv, api, config);
+  siv.run();
+  tui.finish()
+}
+
+/// Everything running behind the views, from when they're set up until the
+/// user quits
+pub struct Tui {
+  /// Sending `None` tells a password prompt that's waiting to give up
+  tx_credential: Arc<Mutex<Sender<Option<String>>>>,
+  /// Set once login has been given up on, so nothing asks again
+  is_quit: Arc<AtomicBool>,
+  status_handle: JoinHandle<Result<(), APIError>>,
+  credits_handle: JoinHandle<Result<(), APIError>>,
+}
+
+impl Tui {
+  /// Sets up the views on `siv` and starts loading data. Nothing is shown
+  /// until `siv` is run.
+  pub fn new<B: DrinkBackend + 'static>(siv: &mut Cursive, mut api: B, config: &Config) -> Tui {
+    let theme_error = config
+      .tui
+      .theme
+      .as_ref()
+      .and_then(|theme| siv.load_theme_file(theme).err());
+    let (tx_credential, rx_credential) = channel();
+    let tx_credential = Arc::new(Mutex::new(tx_credential));
+    let is_quit = Arc::new(AtomicBool::new(false));
+    {
+      let cb_sink = siv.cb_sink().clone();
+      let tx_credential = Arc::clone(&tx_credential);
+      let is_quit = Arc::clone(&is_quit);
+      // Runs on whichever thread needs a login, never the UI thread, so it
+      // can wait on kinit and only touches the dialog through `cb_sink`
+      api.set_password_prompt(Box::new(move |username, password_cb| {
+        if is_quit.load(Ordering::SeqCst) {
+          return;
+        }
+        {
+          let tx_credential = Arc::clone(&tx_credential);
+          let is_quit = Arc::clone(&is_quit);
+          cb_sink
+            .send(Box::new(move |siv| {
+              password_dialog(siv, username, tx_credential, is_quit);
+            }))
+            .ok();
+        }
+        let mut attempts = 0;
+        while let Ok(Some(password)) = rx_credential.recv() {
+          attempts += 1;
+          let result = (password_cb)(password);
+          if is_quit.load(Ordering::SeqCst) {
+            return;
           }
+          let message = match result {
+            Ok(result) if result.success => {
+              cb_sink
+                .send(Box::new(|siv| {
+                  let screen = siv.screen_mut();
+                  if let Some(position) = screen.find_layer_from_name("password_dialog") {
+                    screen.remove_layer(position);
+                  }
+                }))
+                .ok();
+              return;
+            }
+            Ok(result) => result.message.trim().to_string(),
+            Err(err) => format!("Couldn't check your password: {}", err),
+          };
+          cb_sink
+            .send(Box::new(move |siv| {
+              siv.call_on_name("password_message", |view: &mut TextView| {
+                view.set_content(format!("{}\nFailed attempts: {}", message, attempts));
+              });
+              siv.call_on_name("password_entry", |view: &mut EditView| {
+                view.set_content("");
+                view.enable();
+              });
+              siv.focus_name("password_entry").ok();
+            }))
+            .ok();
         }
-      }
-    }));
-    let rx_close = Arc::new(Mutex::new(rx_close));
-    thread::spawn(move || {
-      while let Ok(username) = rx_prompt.recv() {
-        let rx_close = Arc::clone(&rx_close);
-        let tx_credential = Arc::clone(&tx_credential);
-        cb_sink
-          .send(Box::new(move |siv| {
-            siv.add_layer(
-              Dialog::around(
-                LinearLayout::vertical()
-                  .child(TextView::new("Please enter your password").with_name("password_message"))
-                  .child(EditView::new().secret().on_submit(move |siv, password| {
-                    tx_credential
-                      .lock()
-                      .unwrap()
-                      .send(Some(password.to_string()))
-                      .unwrap();
-                    if let Ok(result) = rx_close.lock().unwrap().recv().unwrap() {
-                      if result.success {
-                        siv.pop_layer();
-                      } else {
-                        siv.call_on_name("password_message", move |view: &mut TextView| {
-                          view.set_content(result.message);
-                        });
-                      }
-                    }
-                  })),
-              )
-              .title(format!("Enter login for {}", username))
-              .button("Quit", |siv| {
-                siv.quit();
-              }),
-            );
-          }))
-          .unwrap();
-      }
+        // Cancelled, or the TUI is shutting down
+        is_quit.store(true, Ordering::SeqCst);
+      }));
+    }
+    // api.get_token()?;
+    let model = Arc::new(ModelData {
+      credits: Mutex::new(Store::new(None)),
+      machines: Mutex::new(Store::new(None)),
+      api,
+      keys: KeyBindings::from(&config.tui.keys),
+      confirm_drops: config.confirm_drops.unwrap_or(true),
     });
-  }
-  // api.get_token()?;
-  let model = Arc::new(ModelData {
-    credits: Mutex::new(Store::new(None)),
-    machines: Mutex::new(Store::new(None)),
-    api,
-  });
 
-  // Nice to have
-  siv.add_global_callback('q', |s| s.quit());
+    // Nice to have
+    siv.add_global_callback(model.keys.quit.clone(), |s| s.quit());
 
-  csh_logo(&mut siv);
+    csh_logo(siv);
 
-  let padding = credit_count(Arc::clone(&model), &mut siv);
+    let padding = credit_count(Arc::clone(&model), siv);
 
-  machine_list(Arc::clone(&model), &mut siv, padding);
+    machine_list(Arc::clone(&model), siv, padding);
 
-  let status_handle = {
-    let model = Arc::clone(&model);
-    let cb_sink = siv.cb_sink().clone();
-    thread::spawn(move || {
-      let machine_list = model.api.get_status_for_machine(None)?;
+    if let Some(err) = theme_error {
+      siv.add_layer(
+        Dialog::around(TextView::new(format!("Couldn't load theme: {:?}", err)))
+          .button("Ok", |siv| {
+            siv.pop_layer();
+          })
+          .title("Error"),
+      );
+    }
+
+    let status_handle = {
       let model = Arc::clone(&model);
-      cb_sink
-        .send(Box::new(move |siv| {
-          model.machines.lock().unwrap().set(siv, Some(machine_list));
-        }))
-        .unwrap();
-      Ok(())
-    })
-  };
+      let cb_sink = siv.cb_sink().clone();
+      thread::spawn(move || {
+        let machine_list = model.api.get_status_for_machine(None)?;
+        let model = Arc::clone(&model);
+        cb_sink
+          .send(Box::new(move |siv| {
+            model.machines.lock().unwrap().set(siv, Some(machine_list));
+          }))
+          .unwrap();
+        Ok(())
+      })
+    };
 
-  let credits_handle = {
-    let model = Arc::clone(&model);
-    let cb_sink = siv.cb_sink().clone();
-    thread::spawn(move || {
-      let api = &model.api;
-      let credit_count = api.get_credits()?;
+    let credits_handle = {
       let model = Arc::clone(&model);
-      cb_sink
-        .send(Box::new(move |siv| {
-          model.credits.lock().unwrap().set(siv, Some(credit_count));
-        }))
-        .unwrap();
-      Ok(())
-    })
-  };
+      let cb_sink = siv.cb_sink().clone();
+      thread::spawn(move || {
+        let api = &model.api;
+        let credit_count = api.get_credits()?;
+        let model = Arc::clone(&model);
+        cb_sink
+          .send(Box::new(move |siv| {
+            model.credits.lock().unwrap().set(siv, Some(credit_count));
+          }))
+          .unwrap();
+        Ok(())
+      })
+    };
 
-  siv.run();
+    Tui {
+      tx_credential,
+      is_quit,
+      status_handle,
+      credits_handle,
+    }
+  }
 
-  tx_credential_clone.lock().unwrap().send(None).unwrap();
-  status_handle.join().unwrap()?;
-  credits_handle.join().unwrap()?;
-  Ok(())
+  /// Call once `siv` stops running. Gives up on any login that's waiting for
+  /// a password, then waits for anything still loading.
+  pub fn finish(self) -> Result<(), APIError> {
+    self.is_quit.store(true, Ordering::SeqCst);
+    self.tx_credential.lock().unwrap().send(None).ok();
+    self.status_handle.join().unwrap()?;
+    self.credits_handle.join().unwrap()?;
+    Ok(())
+  }
+}
+
+/// Asks for a password. Submitting hands it to the prompt thread and greys the
+/// dialog out until that thread says how it went.
+fn password_dialog(
+  siv: &mut Cursive,
+  username: String,
+  tx_credential: Arc<Mutex<Sender<Option<String>>>>,
+  is_quit: Arc<AtomicBool>,
+) {
+  let entry = {
+    let tx_credential = Arc::clone(&tx_credential);
+    EditView::new().secret().on_submit(move |siv, password| {
+      if password.is_empty() {
+        return;
+      }
+      if tx_credential
+        .lock()
+        .unwrap()
+        .send(Some(password.to_string()))
+        .is_err()
+      {
+        return;
+      }
+      siv.call_on_name("password_message", |view: &mut TextView| {
+        view.set_content("Authenticating...");
+      });
+      siv.call_on_name("password_entry", |view: &mut EditView| {
+        view.disable();
+      });
+    })
+  };
+  siv.add_layer(
+    Dialog::around(
+      LinearLayout::vertical()
+        .child(TextView::new("Please enter your password").with_name("password_message"))
+        .child(entry.with_name("password_entry")),
+    )
+    .title(format!("Enter login for {}", username))
+    .button("Cancel", move |siv| {
+      is_quit.store(true, Ordering::SeqCst);
+      tx_credential.lock().unwrap().send(None).ok();
+      siv.pop_layer();
+      siv.add_layer(
+        Dialog::around(TextView::new(
+          "Login cancelled, nothing can be loaded without it.",
+        ))
+        .title("Not logged in")
+        .button("Quit", |siv| siv.quit()),
+      );
+    })
+    .button("Quit", |siv| {
+      siv.quit();
+    })
+    .with_name("password_dialog"),
+  );
 }
 
 /// Draws CSH logo in the corner
-fn csh_logo(siv: &mut CursiveRunnable) {
+fn csh_logo(siv: &mut Cursive) {
   let logo = TextView::new(SpannedString::styled(
     include_str!("./logo.txt"),
     Style::from(Effect::Dim).combine(ColorStyle::new(
@@ -172,7 +275,7 @@ fn csh_logo(siv: &mut CursiveRunnable) {
 }
 
 /// Draws credit counter in top-left
-fn credit_count(model: Model, siv: &mut CursiveRunnable) -> Margins {
+fn credit_count<B: DrinkBackend>(model: Model<B>, siv: &mut Cursive) -> Margins {
   let credit_text = TextView::empty();
   let mut listener_view = ListenerView::new(
     credit_text,
@@ -203,7 +306,7 @@ fn credit_count(model: Model, siv: &mut CursiveRunnable) -> Margins {
 }
 
 /// Draws SelectView with list of available machines
-fn machine_list(model: Model, siv: &mut CursiveRunnable, padding: Margins) {
+fn machine_list<B: DrinkBackend + 'static>(model: Model<B>, siv: &mut Cursive, padding: Margins) {
   let mut select: SelectView<Machine> = SelectView::new().h_align(HAlign::Center).autojump();
 
   {
@@ -258,12 +361,12 @@ fn machine_list(model: Model, siv: &mut CursiveRunnable, padding: Margins) {
     .use_store(siv, &mut listener_view);
 
   let listener_view = OnEventView::new(listener_view)
-    .on_event_inner(Key::Right, |listener_view, _event| {
+    .on_event_inner(model.keys.select.clone(), |listener_view, _event| {
       listener_view.with_child::<SelectView<Machine>, _, Option<EventResult>>(|v| {
         Some(v.on_event(Event::Key(Key::Enter)))
       })
     })
-    .on_event(Key::Left, |siv| {
+    .on_event(model.keys.back.clone(), |siv| {
       siv.quit();
     });
 
@@ -279,19 +382,27 @@ fn machine_list(model: Model, siv: &mut CursiveRunnable, padding: Margins) {
 }
 
 /// Draws list of items available for purchase
-fn item_list(model: Model, siv: &mut Cursive, machine_id: u64, padding: Margins) {
+fn item_list<B: DrinkBackend + 'static>(
+  model: Model<B>,
+  siv: &mut Cursive,
+  machine_id: u64,
+  padding: Margins,
+) {
   let mut select: SelectView<Slot> = SelectView::new().h_align(HAlign::Center).autojump();
   {
     let model = Arc::clone(&model);
-    select.set_on_submit(move |siv: &mut Cursive, slot: &Slot| {
-      drop_drink(Arc::clone(&model), siv, slot)
-    });
+    select.set_on_submit(
+      move |siv: &mut Cursive, slot: &Slot| match model.confirm_drops {
+        true => confirm_drop(Arc::clone(&model), siv, slot),
+        false => drop_drink(Arc::clone(&model), siv, slot),
+      },
+    );
   }
   let select = OnEventView::new(select)
-    .on_event_inner(Key::Right, move |select, _event| {
+    .on_event_inner(model.keys.select.clone(), move |select, _event| {
       Some(select.on_event(Event::Key(Key::Enter)))
     })
-    .on_event(Key::Left, |siv| {
+    .on_event(model.keys.back.clone(), |siv| {
       siv.pop_layer();
     });
   let mut listener_view = ListenerView::new(
@@ -357,9 +468,34 @@ fn item_list(model: Model, siv: &mut Cursive, machine_id: u64, padding: Margins)
   ));
 }
 
+/// Shows what a drop will cost before doing it
+fn confirm_drop<B: DrinkBackend + 'static>(model: Model<B>, siv: &mut Cursive, slot: &Slot) {
+  let price = slot.item.price as i64;
+  let mut message = format!("{} costs {} credits.", slot.item.name, price);
+  if let Some(balance) = model.credits.lock().unwrap().get() {
+    message.push_str(&format!(
+      "\nYour balance will go from {} to {}.",
+      balance,
+      balance - price
+    ));
+  }
+  let slot = slot.clone();
+  siv.add_layer(
+    Dialog::around(TextView::new(message))
+      .title("Drop a drink?")
+      .button("Drop", move |siv| {
+        siv.pop_layer();
+        drop_drink(Arc::clone(&model), siv, &slot);
+      })
+      .button("Cancel", |siv| {
+        siv.pop_layer();
+      }),
+  );
+}
+
 /// Fires off a drop and shows a message to the user
 /// Pops off when finished
-fn drop_drink(model: Model, siv: &mut Cursive, slot: &Slot) {
+fn drop_drink<B: DrinkBackend + 'static>(model: Model<B>, siv: &mut Cursive, slot: &Slot) {
   let machine_id = slot.machine;
   let machine_id = model
     .machines
@@ -379,7 +515,17 @@ fn drop_drink(model: Model, siv: &mut Cursive, slot: &Slot) {
   let cb_sink = siv.cb_sink().clone();
   let slot_number = slot.number;
   thread::spawn(move || {
-    match model.api.drop(machine_id, slot_number) {
+    // Check with fresh data, what we're showing could be out of date
+    let mut planned = None;
+    let result = model
+      .api
+      .plan_drop(&machine_id, slot_number)
+      .and_then(|plan| {
+        plan.validate()?;
+        planned = Some(plan);
+        model.api.drop(machine_id, slot_number)
+      });
+    match result {
       Ok(credits) => {
         let message = format!("Enjoy! You now have {} credits", credits);
         let api = &model.api;
@@ -405,10 +551,31 @@ fn drop_drink(model: Model, siv: &mut Cursive, slot: &Slot) {
           }))
           .unwrap();
       }
+      Err(APIError::DropOutcomeUnknown(_)) => {
+        let plan = planned.unwrap();
+        cb_sink
+          .send(Box::new(move |siv| {
+            siv.pop_layer();
+            siv.add_layer(
+              Dialog::around(TextView::new(
+                "Lost track of the drop partway through, so it may or may not have happened.",
+              ))
+              .button("Check balance", move |siv| {
+                siv.pop_layer();
+                check_balance(Arc::clone(&model), siv, plan.clone());
+              })
+              .button("Done", |siv| {
+                siv.pop_layer();
+              })
+              .title("Did it drop?"),
+            );
+          }))
+          .unwrap();
+      }
       Err(err) => {
         let message = match err {
-          APIError::ServerError(_path, message) => message,
-          err => format!("Couldn't drop a drink: {:?}", err),
+          APIError::ServerError(err) => err.message(),
+          err => format!("Couldn't drop a drink: {}", err),
         };
         cb_sink
           .send(Box::new(move |siv| {
@@ -426,3 +593,32 @@ fn drop_drink(model: Model, siv: &mut Cursive, slot: &Slot) {
     };
   });
 }
+
+/// Looks up the balance after a drop we lost track of, to see whether it
+/// went through
+fn check_balance<B: DrinkBackend + 'static>(model: Model<B>, siv: &mut Cursive, plan: DropPlan) {
+  siv.add_layer(Dialog::around(TextView::new("Checking your balance...")).title("Please Wait"));
+  let cb_sink = siv.cb_sink().clone();
+  thread::spawn(move || {
+    let result = model.api.get_credits();
+    cb_sink
+      .send(Box::new(move |siv| {
+        siv.pop_layer();
+        let message = match result {
+          Ok(credits) => {
+            model.credits.lock().unwrap().set(siv, Some(credits));
+            plan.describe_outcome(credits)
+          }
+          Err(err) => format!("Couldn't check your balance either: {}", err),
+        };
+        siv.add_layer(
+          Dialog::around(TextView::new(message))
+            .button("Done", |siv| {
+              siv.pop_layer();
+            })
+            .title("Did it drop?"),
+        );
+      }))
+      .unwrap();
+  });
+}
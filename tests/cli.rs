@@ -0,0 +1,546 @@
+mod common;
+
+use common::MockServer;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+#[test]
+fn list_shows_every_machine() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["list"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert!(output
+    .stdout
+    .contains("Big Drink (bigdrink)\n====================\n1. Coke (50 Credits)\n"));
+  assert!(output
+    .stdout
+    .contains("3. Dr. Pepper (75 Credits) [EMPTY]\n"));
+  assert!(output.stdout.contains("Little Drink (littledrink)\n"));
+}
+
+#[test]
+fn list_one_machine_as_json() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["list", "snack", "--output", "json"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  let json: Value = serde_json::from_str(&output.stdout).unwrap();
+  assert_eq!(json["schema_version"], 1);
+  assert_eq!(json["command"], "list");
+  let machines = json["data"]["machines"].as_array().unwrap();
+  assert_eq!(machines.len(), 1);
+  assert_eq!(machines[0]["slots"][0]["count"], 3);
+}
+
+#[test]
+fn credits() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+
+  let server = MockServer::start(&["--user", "broke"]);
+  assert_eq!(server.clink(&["credits"]).stdout, "5 credits\n");
+}
+
+#[test]
+fn token() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["token"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert!(output.stdout.starts_with("Bearer "));
+  // Comes out of the cache the second time
+  assert_eq!(server.clink(&["token"]).stdout, output.stdout);
+}
+
+#[test]
+fn whoami() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["whoami"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert!(output
+    .stdout
+    .starts_with("user (Test User)\nGroups: member, active\nToken: expires in "));
+
+  let output = server.clink(&["whoami", "--output", "json"]);
+  let json: Value = serde_json::from_str(&output.stdout).unwrap();
+  assert_eq!(json["data"]["username"], "user");
+  assert_eq!(json["data"]["groups"][1], "active");
+  assert!(json["data"]["token_expires_at"].is_u64());
+}
+
+#[test]
+fn login_and_logout() {
+  let server = MockServer::start(&[]);
+  let token = server.clink(&["token"]).stdout;
+  let output = server.clink(&["login"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "Logged in as user\n");
+  let token_after_login = server.clink(&["token"]).stdout;
+  assert_ne!(token_after_login, token);
+
+  let output = server.clink(&["logout"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "Logged out\n");
+  assert_ne!(server.clink(&["token"]).stdout, token_after_login);
+
+  // clink never got a ticket here, so it's not ours to destroy
+  let output = server.clink(&["logout", "--kdestroy"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert!(!output.stdout.contains("Destroyed"));
+}
+
+#[test]
+fn login_needs_a_terminal_for_the_password() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["login", "--password"]);
+  assert_eq!(output.code, 4);
+  assert!(output.stderr.starts_with("Error: NoTTY"));
+}
+
+#[test]
+fn password_sources_that_fail() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["login", "--password", "--password-file", "/nonexistent"]);
+  assert_eq!(output.code, 4);
+  assert!(output
+    .stderr
+    .starts_with("Error: PasswordSourceFailed (Couldn't read /nonexistent: "));
+
+  let output = server.clink(&["login", "--password", "--password-command", "exit 3"]);
+  assert_eq!(output.code, 4);
+  assert_eq!(
+    output.stderr,
+    "Error: PasswordSourceFailed (`exit 3` failed (exit status: 3))\n"
+  );
+
+  let output = server.clink(&["login", "--password", "--keytab", "/nonexistent"]);
+  assert_eq!(
+    output.stderr,
+    "Error: PasswordSourceFailed (There's no keytab at /nonexistent)\n"
+  );
+
+  let output = server.clink(&["login", "--password", "--password-stdin"]);
+  assert_eq!(
+    output.stderr,
+    "Error: PasswordSourceFailed (Got an empty password from stdin)\n"
+  );
+}
+
+#[test]
+fn server_flags_go_anywhere() {
+  let server = MockServer::start(&[]);
+  let url = server.url();
+  let output = server.run_clink(&[], &["credits", "--api", url, "--sso-url", url]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+}
+
+#[test]
+fn doctor() {
+  // Whether the Kerberos checks pass depends on the machine, so only look at
+  // the ones the mock server decides
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["doctor"]);
+  assert!(output.stdout.contains("\n[ ok ] SSO reachable: "));
+  assert!(output
+    .stdout
+    .contains("\n[ ok ] SSO token: Got one, expires in "));
+  assert!(output.stdout.contains("\n[ ok ] Drink list: 3 machines\n"));
+
+  // Connecting ourselves proves nothing when there's a proxy in the way
+  let env = [("http_proxy", "http://127.0.0.1:1")];
+  let output = server.clink_with_env(&env, &["doctor"]);
+  assert!(
+    output
+      .stdout
+      .contains("\n[skip] Drink API reachable: Going through a proxy (http://127.0.0.1:1)\n"),
+    "{}",
+    output.stdout
+  );
+
+  // A cached token doesn't mean SPNEGO still works
+  assert_eq!(server.clink(&["credits"]).code, 0);
+  let sso = MockServer::start(&["--no-kerberos"]);
+  let args = ["--api", server.url(), "--sso-url", sso.url(), "doctor"];
+  let output = server.run_clink(&[], &args);
+  assert!(
+    output
+      .stdout
+      .contains("\n[FAIL] SSO token: SSO didn't redirect us with a token"),
+    "{}",
+    output.stdout
+  );
+
+  // The drink list check uses the same HTTP settings as everything else
+  let server = MockServer::start(&["--flaky", "/drinks"]);
+  let output = server.clink(&["doctor", "--retries", "0"]);
+  assert!(
+    output.stdout.contains("\n[FAIL] Drink list: "),
+    "{}",
+    output.stdout
+  );
+
+  let server = MockServer::start(&["--malformed", "/drinks"]);
+  let output = server.clink(&["doctor", "--output", "json"]);
+  assert_eq!(output.code, 1);
+  let json: Value = serde_json::from_str(&output.stdout).unwrap();
+  let checks = json["data"]["checks"].as_array().unwrap();
+  let drinks = checks.last().unwrap();
+  assert_eq!(drinks["name"], "Drink list");
+  assert_eq!(drinks["status"], "fail");
+  assert!(drinks["tip"].as_str().unwrap().contains("--verbose"));
+}
+
+#[test]
+fn config_hides_secrets() {
+  let server = MockServer::start(&[]);
+  for (key, value) in [
+    ("auth.token", "hunter2"),
+    ("profile.work.auth.refresh_token", "swordfish"),
+  ] {
+    let output = server.run_clink(&[], &["config", "set", key, value]);
+    assert_eq!(output.code, 0, "{}", output.stderr);
+  }
+  let stdout = |args: &[&str]| server.run_clink(&[], args).stdout;
+  let list = stdout(&["config", "list"]);
+  assert!(list.contains("\nauth.token = <redacted>\n"), "{}", list);
+  assert!(list.contains("\nprofile.work.auth.refresh_token = <redacted>\n"));
+  assert!(!list.contains("hunter2") && !list.contains("swordfish"));
+  assert!(!stdout(&["config", "get", "auth"]).contains("hunter2"));
+  // Unless you ask for them
+  assert_eq!(stdout(&["config", "get", "auth.token"]), "hunter2\n");
+  let list = stdout(&["config", "list", "--show-secrets"]);
+  assert!(list.contains("\nauth.token = \"hunter2\"\n"), "{}", list);
+  assert!(stdout(&["config", "get", "profile", "--show-secrets"]).contains("swordfish"));
+}
+
+#[test]
+fn profiles() {
+  let server = MockServer::start(&[]);
+  let broke = MockServer::start(&["--user", "broke"]);
+  for (key, value) in [
+    ("api", server.url()),
+    ("sso.url", server.url()),
+    ("profile.broke.api", broke.url()),
+    ("profile.broke.sso.url", broke.url()),
+  ] {
+    let output = server.run_clink(&[], &["config", "set", key, value]);
+    assert_eq!(output.code, 0, "{}", output.stderr);
+  }
+  let credits = |env: &[(&str, &str)], args: &[&str]| server.run_clink(env, args).stdout;
+  assert_eq!(credits(&[], &["credits"]), "200 credits\n");
+  assert_eq!(
+    credits(&[], &["--profile", "broke", "credits"]),
+    "5 credits\n"
+  );
+  assert_eq!(
+    credits(&[("CLINK_PROFILE", "broke")], &["credits"]),
+    "5 credits\n"
+  );
+  // Each profile gets its own token
+  let cache = server.home().join("cache/clink");
+  assert!(cache.join("token.json").is_file());
+  assert!(cache.join("profiles/broke/token.json").is_file());
+  // Your Kerberos tickets are fine for a profile that's still you
+  let doctor = |env: &[(&str, &str)]| server.run_clink(env, &["--profile", "broke", "doctor"]);
+  let output = doctor(&[]);
+  assert!(
+    !output.stdout.contains("profiles/broke/krb5cc"),
+    "{}",
+    output.stdout
+  );
+  // But one for someone else gets its own, so it can't borrow yours
+  let output = server.run_clink(&[], &["config", "set", "profile.broke.username", "broke"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  let output = doctor(&[]);
+  assert!(
+    output.stdout.contains("profiles/broke/krb5cc"),
+    "{}",
+    output.stdout
+  );
+  // Unless you've said which ones to use
+  let output = doctor(&[("KRB5CCNAME", "FILE:/nonexistent/krb5cc_broke")]);
+  assert!(
+    !output.stdout.contains("profiles/broke/krb5cc"),
+    "{}",
+    output.stdout
+  );
+  assert!(
+    output.stdout.contains("/nonexistent/krb5cc_broke"),
+    "{}",
+    output.stdout
+  );
+
+  let output = server.run_clink(&[], &["profile", "use", "broke"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(credits(&[], &["credits"]), "5 credits\n");
+  assert_eq!(
+    server.run_clink(&[], &["profile", "list"]).stdout,
+    format!("* broke  {}\n", broke.url())
+  );
+
+  let output = server.run_clink(&[], &["profile", "use", "nope"]);
+  assert_eq!(output.code, 1);
+  assert_eq!(
+    output.stderr,
+    "Error: There's no [profile.nope] in your config\n"
+  );
+  server.run_clink(&[], &["profile", "use", "--none"]);
+  assert_eq!(credits(&[], &["credits"]), "200 credits\n");
+}
+
+#[test]
+fn drop_takes_credits() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "--yes", "bigdrink", "1"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "Item dropped! Your new balance is 150\n");
+  assert_eq!(server.clink(&["credits"]).stdout, "150 credits\n");
+}
+
+#[test]
+fn drop_by_name() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "--yes", "chips"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(
+    output.stderr,
+    "Dropping Chips (25 Credits) from Snack, slot 1\n"
+  );
+  assert_eq!(output.stdout, "Item dropped! Your new balance is 175\n");
+
+  let output = server.clink(&["list", "snack", "--output", "csv"]);
+  assert!(output
+    .stdout
+    .contains("\nsnack,Snack,true,1,5,Chips,25,true,false,2\n"));
+}
+
+#[test]
+fn drop_needs_confirmation_without_a_terminal() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "bigdrink", "1"]);
+  assert_eq!(output.code, 9);
+  assert!(output.stderr.starts_with("Error: NeedsConfirmation"));
+  assert_eq!(server.clink(&["credits"]).stdout, "200 credits\n");
+}
+
+#[test]
+fn drop_dry_run() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "--dry-run", "bigdrink", "2"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(
+    output.stdout,
+    "Would drop Sprite from bigdrink, slot 2. Your new balance would be 150\n"
+  );
+  assert_eq!(server.clink(&["credits"]).stdout, "200 credits\n");
+}
+
+#[test]
+fn drop_from_offline_machine() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "--yes", "littledrink", "1"]);
+  assert_eq!(output.code, 10);
+  assert_eq!(
+    output.stderr,
+    "Error: MachineOffline (Little Drink is offline)\n"
+  );
+}
+
+#[test]
+fn drop_from_empty_slot() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["drop", "--yes", "bigdrink", "3"]);
+  assert_eq!(output.code, 11);
+  assert_eq!(
+    output.stderr,
+    "Error: SlotEmpty (Slot 3 in Big Drink is empty)\n"
+  );
+}
+
+#[test]
+fn drop_without_enough_credits() {
+  let server = MockServer::start(&["--user", "broke"]);
+  let output = server.clink(&["drop", "--yes", "bigdrink", "1"]);
+  assert_eq!(output.code, 13);
+
+  // The server should still say no when we don't check first
+  let output = server.clink(&["drop", "--yes", "--force", "bigdrink", "1"]);
+  assert_eq!(output.code, 7);
+  assert!(output.stderr.contains("402 Payment Required"));
+  assert!(output.stderr.ends_with(": Not enough credits\n"));
+}
+
+#[test]
+fn unauthorized() {
+  // Every token is expired by the time we use it
+  let server = MockServer::start(&["--token-lifetime", "0"]);
+  let output = server.clink(&["credits"]);
+  assert_eq!(output.code, 3);
+  assert!(output.stderr.starts_with("Error: Unauthorized"));
+}
+
+#[test]
+fn revoked_token_is_replaced() {
+  let server = MockServer::start(&["--expire-tokens"]);
+  // credits makes two requests, so the second one gets a 401 and has to
+  // fetch a new token
+  let output = server.clink(&["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+}
+
+#[test]
+fn static_token() {
+  let server = MockServer::start(&["--api-key", "hunter2"]);
+  let output = server.clink_with_env(&[("CLINK_TOKEN", "hunter2")], &["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+
+  // No falling back to Kerberos when the token is bad
+  let output = server.clink_with_env(&[("CLINK_TOKEN", "wrong")], &["credits"]);
+  assert_eq!(output.code, 3);
+
+  let output = server.clink(&["--auth", "token", "credits"]);
+  assert_eq!(output.code, 2);
+  assert!(output.stderr.contains("needs CLINK_TOKEN"));
+}
+
+#[test]
+fn refresh_token() {
+  let server = MockServer::start(&["--refresh-token", "first", "--expire-tokens"]);
+  let env = [
+    ("CLINK_AUTH", "refresh-token"),
+    ("CLINK_REFRESH_TOKEN", "first"),
+  ];
+  // The second request gets a 401, so this only works if we kept the
+  // refresh token SSO rotated to
+  let output = server.clink_with_env(&env, &["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+
+  // The rotated refresh token is kept in the token cache, so the next run
+  // doesn't need "first"
+  let output = server.clink_with_env(&env, &["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+
+  // doctor goes through the cache too, instead of spending "first" again
+  let output = server.clink_with_env(&env, &["doctor"]);
+  assert!(
+    output.stdout.contains("[ ok ] SSO token"),
+    "{}",
+    output.stdout
+  );
+  let output = server.clink_with_env(&env, &["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+
+  // Without the cache all we have is "first", and that's been used up
+  server.clink(&["logout"]);
+  let output = server.clink_with_env(&env, &["credits"]);
+  assert_eq!(output.code, 3);
+  assert!(
+    output.stderr.contains("refresh token expired"),
+    "{}",
+    output.stderr
+  );
+}
+
+#[test]
+fn device_login() {
+  // Tokens are stale as soon as we get them, so every run has to refresh
+  let server = MockServer::start(&["--no-kerberos", "--token-lifetime", "30"]);
+  // Without SSO taking tickets, nothing short of a device login gets us in.
+  // A password command that gives up stands in for someone who can't kinit.
+  let output = server.clink(&["--password-command", "false", "credits"]);
+  assert_eq!(output.code, 4);
+  assert!(output.stderr.starts_with("Error: PasswordSourceFailed"));
+
+  let output = server.clink(&["login", "--device"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "Logged in as user\n");
+  assert!(output.stderr.starts_with(&format!(
+    "To log in, open {}/device and enter MOCK-CODE\n",
+    server.url()
+  )));
+
+  // No Kerberos, so this only works if the refresh token does
+  let output = server.clink(&["credits"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+  assert_eq!(output.stdout, "200 credits\n");
+}
+
+#[test]
+fn hung_server_times_out() {
+  let server = MockServer::start(&["--delay", "5000"]);
+  let started = Instant::now();
+  let output = server.clink(&["--timeout", "1", "list"]);
+  assert_eq!(output.code, 5);
+  assert!(output.stderr.contains("timeout"), "{}", output.stderr);
+  assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn retries_flaky_server() {
+  let flaky = ["--flaky", "/drinks", "--flaky", "/drinks"];
+  let server = MockServer::start(&flaky);
+  let output = server.clink(&["list", "snack"]);
+  assert_eq!(output.code, 0, "{}", output.stderr);
+
+  let server = MockServer::start(&flaky);
+  let output = server.clink(&["--retries", "0", "list", "snack"]);
+  assert_eq!(output.code, 8);
+  assert!(output.stderr.contains("503"), "{}", output.stderr);
+}
+
+#[test]
+fn drop_outcome_unknown() {
+  // Only the first drop fails, so trying again would go through
+  let server = MockServer::start(&["--flaky", "/drinks/drop"]);
+  let output = server.clink(&["drop", "--yes", "bigdrink", "1"]);
+  assert_eq!(output.code, 17);
+  assert!(output.stderr.starts_with("Error: DropOutcomeUnknown"));
+  assert!(output.stderr.contains("clink credits"), "{}", output.stderr);
+  // Nothing was dropped, so it wasn't retried
+  assert_eq!(server.clink(&["credits"]).stdout, "200 credits\n");
+}
+
+#[test]
+fn server_error() {
+  let server = MockServer::start(&["--fail", "/drinks"]);
+  let output = server.clink(&["list"]);
+  assert_eq!(output.code, 8);
+  assert_eq!(
+    output.stderr,
+    format!(
+      "Error: ServerError (500 Internal Server Error) for {}/drinks: Internal Server Error\n",
+      server.url()
+    )
+  );
+  assert_eq!(output.stdout, "");
+}
+
+#[test]
+fn unknown_machine() {
+  let server = MockServer::start(&[]);
+  let output = server.clink(&["list", "nope"]);
+  assert_eq!(output.code, 7);
+  assert!(output.stderr.ends_with(": Invalid machine name\n"));
+}
+
+#[test]
+fn malformed_json() {
+  let server = MockServer::start(&["--malformed", "/drinks"]);
+  let output = server.clink(&["list"]);
+  assert_eq!(output.code, 6);
+  assert_eq!(
+    output.stderr,
+    "Error: BadFormat (The server sent data we didn't understand)\n"
+  );
+
+  let output = server.clink(&["list", "--verbose"]);
+  assert_eq!(output.code, 6);
+  assert!(output
+    .stderr
+    .contains("\n  Caused by: Couldn't parse the response from "));
+  assert!(output.stderr.contains("\n  Caused by: EOF while parsing"));
+}
@@ -0,0 +1,105 @@
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use tempfile::TempDir;
+
+/// A `clink-mock-server` for one test, killed when it's dropped. Every server
+/// gets its own config and cache directories, so tokens and config files
+/// don't leak between tests.
+pub struct MockServer {
+  process: Child,
+  url: String,
+  home: TempDir,
+}
+
+/// What a `clink` run printed and exited with
+pub struct Output {
+  pub code: i32,
+  pub stdout: String,
+  pub stderr: String,
+}
+
+impl MockServer {
+  /// Starts the server with the default fixture and `args` (like
+  /// `--offline bigdrink`), and waits until it's listening
+  pub fn start(args: &[&str]) -> MockServer {
+    let mut process = Command::new(env!("CARGO_BIN_EXE_clink-mock-server"))
+      .args(args)
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn()
+      .expect("couldn't start clink-mock-server");
+    let mut line = String::new();
+    BufReader::new(process.stdout.take().unwrap())
+      .read_line(&mut line)
+      .unwrap();
+    let url = line
+      .trim()
+      .strip_prefix("Listening on ")
+      .unwrap_or_else(|| panic!("unexpected output from clink-mock-server: {:?}", line))
+      .to_string();
+    MockServer {
+      process,
+      url,
+      home: TempDir::new().unwrap(),
+    }
+  }
+
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// Where `clink` keeps its config (`config/`) and cache (`cache/`)
+  pub fn home(&self) -> &Path {
+    self.home.path()
+  }
+
+  /// Runs `clink` against this server, with nothing on stdin and no
+  /// controlling terminal
+  pub fn clink(&self, args: &[&str]) -> Output {
+    self.clink_with_env(&[], args)
+  }
+
+  /// [`MockServer::clink`], with some environment variables set
+  pub fn clink_with_env(&self, env: &[(&str, &str)], args: &[&str]) -> Output {
+    let mut server_args = vec!["--api", &self.url, "--sso-url", &self.url];
+    server_args.extend_from_slice(args);
+    self.run_clink(env, &server_args)
+  }
+
+  /// Runs `clink` with this server's config and cache directories, but
+  /// without pointing it at the server, for testing the config itself
+  pub fn run_clink(&self, env: &[(&str, &str)], args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_clink"));
+    command
+      .args(args)
+      .env_clear()
+      .envs(env.iter().copied())
+      .env("HOME", self.home.path())
+      .env("XDG_CONFIG_HOME", self.home.path().join("config"))
+      .env("XDG_CACHE_HOME", self.home.path().join("cache"))
+      .stdin(Stdio::null());
+    // A session of its own, so password prompts can't find the terminal
+    // the tests were started from through /dev/tty
+    unsafe {
+      command.pre_exec(|| match libc::setsid() {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+      });
+    }
+    let output = command.output().expect("couldn't run clink");
+    Output {
+      code: output.status.code().expect("clink was killed"),
+      stdout: String::from_utf8(output.stdout).unwrap(),
+      stderr: String::from_utf8(output.stderr).unwrap(),
+    }
+  }
+}
+
+impl Drop for MockServer {
+  fn drop(&mut self) {
+    self.process.kill().ok();
+    self.process.wait().ok();
+  }
+}